@@ -0,0 +1,52 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use grep::{run_with_writer, Config};
+use memchr::memmem::Finder;
+use std::fs;
+use std::io;
+
+// About 8 MB of lines of `width` bytes, with the needle on one line in a thousand
+fn haystack(width: usize) -> String {
+    let filler = "thread picked up a job ".repeat(width / 23 + 1);
+    (0..8_000_000 / width)
+        .map(|i| {
+            let needle = if i % 1000 == 0 { "needle" } else { "" };
+            format!("{}{}\n", &filler[..width - 1 - needle.len()], needle)
+        })
+        .collect()
+}
+
+// The line scan before and after switching the case-sensitive literal path to memmem
+fn line_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("literal line scan");
+    for width in [80, 4096] {
+        let contents = haystack(width);
+        group.throughput(Throughput::Bytes(contents.len() as u64));
+        group.bench_with_input(BenchmarkId::new("str::contains", width), &contents, |b, text| {
+            b.iter(|| text.lines().filter(|line| line.contains("needle")).count())
+        });
+        let finder = Finder::new("needle");
+        group.bench_with_input(BenchmarkId::new("memmem::Finder", width), &contents, |b, text| {
+            b.iter(|| text.lines().filter(|line| finder.find(line.as_bytes()).is_some()).count())
+        });
+    }
+    group.finish();
+}
+
+// The whole search of one file through the library, output thrown away
+fn whole_search(c: &mut Criterion) {
+    let contents = haystack(80);
+    let path = std::env::temp_dir().join(format!("grep-bench-{}.txt", std::process::id()));
+    fs::write(&path, &contents).unwrap();
+    let config = Config::build_from_slice(&["needle", path.to_str().unwrap()]).unwrap();
+
+    let mut group = c.benchmark_group("literal search");
+    group.throughput(Throughput::Bytes(contents.len() as u64));
+    group.bench_function("run_with_writer", |b| {
+        b.iter(|| run_with_writer(&config, &mut io::sink()).unwrap())
+    });
+    group.finish();
+    let _ = fs::remove_file(&path);
+}
+
+criterion_group!(benches, line_scan, whole_search);
+criterion_main!(benches);
@@ -1,245 +1,3278 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use glob::glob;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, IsTerminal, LineWriter, Write};
+use std::ops::{ControlFlow, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use flate2::read::MultiGzDecoder;
+use glob::{glob, Pattern};
+use ignore::WalkBuilder;
+use memchr::memmem::Finder;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
 use walkdir::WalkDir;
 use colored::*;
 /**
  * 
  * 
-Usage: grep [OPTIONS] <pattern> <files...>
+Usage: grep [OPTIONS] <pattern> [files...]
+Reads standard input when no files are given
+Files may be wildcards such as *.md, where ** spans directories as in src/**/*.rs
+Exits 0 if a line was selected, 1 if none was, and 2 on an error
+Short options can be combined, as in -in or -nA3
+With -r, files and directories matching a glob in DIR/.searchignore (one per line, # starts a comment) are never searched, even with --no-ignore
+An empty pattern selects every line; it matches no text, so -o prints nothing for it
+GREP_COLORS sets the ms, fn, ln and se colors as GNU grep does, e.g. ms=01;31:fn=35 (--color-* options win)
 Options:
--i                Case-insensitive search
--n                Print line numbers
--v                Invert match (exclude lines that match the pattern)
--r                Recursive directory search
--f                Print filenames
--c                Enable colored output
+-i, --ignore-case Case-insensitive search, by Unicode simple case folding (σ matches ς, but ß doesn't match ss)
+-n, --line-number Print line numbers
+-v, --invert-match Invert match (exclude lines that match the pattern)
+-r, --recursive   Recursive directory search (of the current directory if no files are given)
+-f, --with-filename Print filenames (automatic when searching several files or recursively)
+-c, --color[=WHEN] Color matches; WHEN is auto (the default: only on a terminal), always or never. -c means always
+-E, --regex       Interpret the pattern as a regular expression
+-A NUM            Print NUM lines of trailing context after each match
+-B NUM            Print NUM lines of leading context before each match
+-C NUM            Print NUM lines of context on both sides (a later -A/-B wins)
+-o, --only-matching Print only the matched parts of each line
+--count           Print only a count of selected lines per file (can't be combined with --count-matches)
+-m, --max-count NUM Stop reading a file after NUM selected lines
+-l                Print only the names of files with a selected line
+-L                Print only the names of files without a selected line
+-q, --quiet       Print nothing; exit 0 on the first match, 1 if none
+-s, --no-messages Suppress error messages about unreadable files
+-w                Match only whole words
+-x                Match only whole lines
+-e PATTERN        Use PATTERN; repeat to match any of several patterns
+--patterns-file PATH Read patterns from PATH, one per line (blank lines are ignored)
+--smart-case      Ignore case unless a pattern contains an uppercase letter (-i wins)
+--threads NUM     Search recursively with NUM threads (0 picks one per CPU, 1 is serial). Files searched ahead of their turn keep up to 1 MiB of output each in memory; a file with more is searched again when its turn comes
+--color-match COLOR Highlight matches in COLOR (a name such as yellow or bright blue; red by default)
+--no-filename     Never print filenames; beats both -f and the automatic prefix
+--heading         Print each file's path once above its numbered lines, with a blank line between files
+--vimgrep         Print file:line:column:text for every match, the column being a 1-based byte offset
+--json            Print one JSON object per selected line, with the byte offsets of each match
+--no-ignore       With -r, also search files that .gitignore/.ignore rules would skip
+--gitignore       With -r, skip files that .gitignore/.ignore rules exclude (the default; undoes --no-ignore)
+--include GLOB    With -r, only search files whose name matches GLOB (repeatable)
+--exclude GLOB    With -r, skip files whose name matches GLOB (repeatable; beats --include)
+--max-depth NUM   With -r, descend at most NUM levels; the directory given is depth 0, so 1 searches only its own files
+-a, --text        Search binary files (a NUL byte near the start) as text instead of just reporting a match
+-z, --null-data   Input and output lines end with a NUL byte instead of a newline
+-Z, --null        End each printed filename with a NUL byte instead of ':' (or a newline with -l/-L)
+-b, --byte-offset Print the 0-based byte offset of each line's start (of each match with -o) before it
+--replace TEXT    Print selected lines with every match replaced by TEXT (files are left untouched). With -E, $1 or ${name} inserts a capture group and $$ a literal $
+--in-place[=SUFFIX] With --replace, rewrite the files themselves, keeping the original as FILE+SUFFIX if one is given (not with -v, --pre or --search-zip)
+--stats           After the results, print counts of files searched, lines selected and matches, and the time taken, to stderr
+--max-filesize SIZE With -r, skip files larger than SIZE bytes; K, M and G suffixes multiply by 1024
+--hidden          With -r, also search hidden files and directories (names starting with '.')
+-t, --type TYPE   With -r, only search files of TYPE, such as rust or markdown (repeatable)
+--type-list       Show the types -t knows and their extensions
+--search-zip      Decompress files ending in .gz and search their contents
+--count-matches, --only-count-matches Print only a count of matches per file, counting every occurrence on a line, as rg -co does
+--group-separator STR Separate groups of context lines with STR instead of --
+--no-group-separator Don't print anything between groups of context lines
+--line-number-width[=N] With -n, right-align line numbers to N columns, or to fit the file's last line number if N is left out
+--color-path COLOR Color filenames in COLOR when coloring (magenta by default)
+--color-line COLOR Color line numbers and byte offsets in COLOR when coloring (green by default)
+--color-separator COLOR Color the separators after filenames and line numbers, and between context groups, in COLOR (cyan by default)
+--follow          With -r, follow symbolic links to directories (a link back to an enclosing directory is not walked again)
+--files           List the files a search would read (walking directories as -r does) without searching them; takes no pattern
+--total           With --count or --count-matches, finish with a total: N line summing the counts of every file
+-F, --fixed-strings Match the pattern literally, even with -E
+--all-match       With several patterns, select only lines that match every one of them (not just any)
+--and-not PATTERN Reject lines that also contain PATTERN (honors -i, -E and -F)
+--fuzzy NUM       Experimental: select lines where some stretch of text is within NUM edits of a pattern, taken literally. Much slower than a normal search, and matches aren't highlighted
+--multiline       With -E, match against the whole file so a match can span lines; every line a match touches is selected, so -A/-B count from a match's first and last lines
+--trim            Strip leading and trailing whitespace from printed lines (matching still sees the whole line; -b points at the first printed byte)
+--max-columns NUM Print at most NUM characters of each line, marking a cut with …; matching still sees the whole line
+--max-columns-preview With --max-columns, show the part of a long line around its first match instead of its start
+--pre COMMAND     Search what COMMAND prints when run with each file's path as its argument (e.g. pdftotext) instead of the file itself. COMMAND runs on every file searched, so only use one you trust
+--field-separator STR Put STR instead of ': ' between the filename, line number, offset and text of selected lines (and of --count lines); context lines then use a bare '-'
+--binary          Report each match in a binary file as its byte offset (Binary file F matches at offset N) instead of a single line for the file
+--dotall          With -E, let . match a newline too (only useful with --multiline or -z)
+--multiline-regex With --multiline, let ^ and $ match at every line break; otherwise they only match at the start and end of the file
+--max-matches NUM Stop the whole search once NUM lines have been selected, across all files (-m is per file)
+--o-separator STR With -o, print all the matches of a line on one line, joined by STR
+--path-separator CHAR Print paths with CHAR in place of the platform's path separators (e.g. / on Windows); files are still opened by their real paths
+--files-from PATH Also search the files listed in PATH, one per line (- reads the list from standard input; blank lines and # comments are skipped)
+--sort KEY        With -r, search all the files found, across the whole tree, ordered by KEY: path, modified (oldest first) or size (smallest first); ties go by path
+--sortr KEY       Like --sort, but in reverse, e.g. --sortr modified puts the most recently modified files first
 -h, --help        Show help information
  * 
  * 
 */
+const USAGE: &str = "Usage: grep [OPTIONS] <pattern> [files...]\nReads standard input when no files are given\nFiles may be wildcards such as *.md, where ** spans directories as in src/**/*.rs\nExits 0 if a line was selected, 1 if none was, and 2 on an error\nShort options can be combined, as in -in or -nA3\nWith -r, files and directories matching a glob in DIR/.searchignore (one per line, # starts a comment) are never searched, even with --no-ignore\nAn empty pattern selects every line; it matches no text, so -o prints nothing for it\nGREP_COLORS sets the ms, fn, ln and se colors as GNU grep does, e.g. ms=01;31:fn=35 (--color-* options win)\nOptions:\n-i, --ignore-case\tCase-insensitive search, by Unicode simple case folding (σ matches ς, but ß doesn't match ss)\n-n, --line-number\tPrint line numbers\n-v, --invert-match\tInvert match (exclude lines that match the pattern)\n-r, --recursive\tRecursive directory search (of the current directory if no files are given)\n-f, --with-filename\tPrint filenames (automatic when searching several files or recursively)\n-c, --color[=WHEN]\tColor matches; WHEN is auto (the default: only on a terminal), always or never. -c means always\n-E, --regex\tInterpret the pattern as a regular expression\n-A NUM\tPrint NUM lines of trailing context after each match\n-B NUM\tPrint NUM lines of leading context before each match\n-C NUM\tPrint NUM lines of context on both sides (a later -A/-B wins)\n-o, --only-matching\tPrint only the matched parts of each line\n--count\tPrint only a count of selected lines per file (can't be combined with --count-matches)\n-m, --max-count NUM\tStop reading a file after NUM selected lines\n-l\tPrint only the names of files with a selected line\n-L\tPrint only the names of files without a selected line\n-q, --quiet\tPrint nothing; exit 0 on the first match, 1 if none\n-s, --no-messages\tSuppress error messages about unreadable files\n-w\tMatch only whole words\n-x\tMatch only whole lines\n-e PATTERN\tUse PATTERN; repeat to match any of several patterns\n--patterns-file PATH\tRead patterns from PATH, one per line (blank lines are ignored)\n--smart-case\tIgnore case unless a pattern contains an uppercase letter (-i wins)\n--threads NUM\tSearch recursively with NUM threads (0 picks one per CPU, 1 is serial). Files searched ahead of their turn keep up to 1 MiB of output each in memory; a file with more is searched again when its turn comes\n--color-match COLOR\tHighlight matches in COLOR (a name such as yellow or bright blue; red by default)\n--no-filename\tNever print filenames; beats both -f and the automatic prefix\n--heading\tPrint each file's path once above its numbered lines, with a blank line between files\n--vimgrep\tPrint file:line:column:text for every match, the column being a 1-based byte offset\n--json\tPrint one JSON object per selected line, with the byte offsets of each match\n--no-ignore\tWith -r, also search files that .gitignore/.ignore rules would skip\n--gitignore\tWith -r, skip files that .gitignore/.ignore rules exclude (the default; undoes --no-ignore)\n--include GLOB\tWith -r, only search files whose name matches GLOB (repeatable)\n--exclude GLOB\tWith -r, skip files whose name matches GLOB (repeatable; beats --include)\n--max-depth NUM\tWith -r, descend at most NUM levels; the directory given is depth 0, so 1 searches only its own files\n-a, --text\tSearch binary files (a NUL byte near the start) as text instead of just reporting a match\n-z, --null-data\tInput and output lines end with a NUL byte instead of a newline\n-Z, --null\tEnd each printed filename with a NUL byte instead of ':' (or a newline with -l/-L)\n-b, --byte-offset\tPrint the 0-based byte offset of each line's start (of each match with -o) before it\n--replace TEXT\tPrint selected lines with every match replaced by TEXT (files are left untouched). With -E, $1 or ${name} inserts a capture group and $$ a literal $\n--in-place[=SUFFIX]\tWith --replace, rewrite the files themselves, keeping the original as FILE+SUFFIX if one is given (not with -v, --pre or --search-zip)\n--stats\tAfter the results, print counts of files searched, lines selected and matches, and the time taken, to stderr\n--max-filesize SIZE\tWith -r, skip files larger than SIZE bytes; K, M and G suffixes multiply by 1024\n--hidden\tWith -r, also search hidden files and directories (names starting with '.')\n-t, --type TYPE\tWith -r, only search files of TYPE, such as rust or markdown (repeatable)\n--type-list\tShow the types -t knows and their extensions\n--search-zip\tDecompress files ending in .gz and search their contents\n--count-matches, --only-count-matches\tPrint only a count of matches per file, counting every occurrence on a line, as rg -co does\n--group-separator STR\tSeparate groups of context lines with STR instead of --\n--no-group-separator\tDon't print anything between groups of context lines\n--line-number-width[=N]\tWith -n, right-align line numbers to N columns, or to fit the file's last line number if N is left out\n--color-path COLOR\tColor filenames in COLOR when coloring (magenta by default)\n--color-line COLOR\tColor line numbers and byte offsets in COLOR when coloring (green by default)\n--color-separator COLOR\tColor the separators after filenames and line numbers, and between context groups, in COLOR (cyan by default)\n--follow\tWith -r, follow symbolic links to directories (a link back to an enclosing directory is not walked again)\n--files\tList the files a search would read (walking directories as -r does) without searching them; takes no pattern\n--total\tWith --count or --count-matches, finish with a total: N line summing the counts of every file\n-F, --fixed-strings\tMatch the pattern literally, even with -E\n--all-match\tWith several patterns, select only lines that match every one of them (not just any)\n--and-not PATTERN\tReject lines that also contain PATTERN (honors -i, -E and -F)\n--fuzzy NUM\tExperimental: select lines where some stretch of text is within NUM edits of a pattern, taken literally. Much slower than a normal search, and matches aren't highlighted\n--multiline\tWith -E, match against the whole file so a match can span lines; every line a match touches is selected, so -A/-B count from a match's first and last lines\n--trim\tStrip leading and trailing whitespace from printed lines (matching still sees the whole line; -b points at the first printed byte)\n--max-columns NUM\tPrint at most NUM characters of each line, marking a cut with …; matching still sees the whole line\n--max-columns-preview\tWith --max-columns, show the part of a long line around its first match instead of its start\n--pre COMMAND\tSearch what COMMAND prints when run with each file's path as its argument (e.g. pdftotext) instead of the file itself. COMMAND runs on every file searched, so only use one you trust\n--field-separator STR\tPut STR instead of ': ' between the filename, line number, offset and text of selected lines (and of --count lines); context lines then use a bare '-'\n--binary\tReport each match in a binary file as its byte offset (Binary file F matches at offset N) instead of a single line for the file\n--dotall\tWith -E, let . match a newline too (only useful with --multiline or -z)\n--multiline-regex\tWith --multiline, let ^ and $ match at every line break; otherwise they only match at the start and end of the file\n--max-matches NUM\tStop the whole search once NUM lines have been selected, across all files (-m is per file)\n--o-separator STR\tWith -o, print all the matches of a line on one line, joined by STR\n--path-separator CHAR\tPrint paths with CHAR in place of the platform's path separators (e.g. / on Windows); files are still opened by their real paths\n--files-from PATH\tAlso search the files listed in PATH, one per line (- reads the list from standard input; blank lines and # comments are skipped)\n--sort KEY\tWith -r, search all the files found, across the whole tree, ordered by KEY: path, modified (oldest first) or size (smallest first); ties go by path\n--sortr KEY\tLike --sort, but in reverse, e.g. --sortr modified puts the most recently modified files first\n-h, --help\tShow help information";
 
 // refer to the io project in the Rust book
+#[derive(Clone)]
 pub struct Config {
-    pub query: String,
+    // A line matches if it contains any of these (all of them with --all-match)
+    pub queries: Vec<String>,
     pub file_paths: Vec<PathBuf>,
     pub case_insensitive: bool,
     pub line_number: bool,
+    // --line-number-width: pad line numbers on the left to this many columns. Some(0) sizes
+    // them to fit the file's line count, which means reading the whole file before printing
+    pub line_number_width: Option<usize>,
     pub invert_match: bool,
     pub recursive_search:bool,
+    // Whether -r honors .gitignore/.ignore files
+    pub respect_ignore: bool,
+    // Whether -r visits entries whose names start with '.'
+    pub hidden: bool,
+    // Whether -r walks into symlinked directories
+    pub follow_links: bool,
+    // --sort/--sortr: search all the files -r finds in this order instead of the walk's
+    pub sort: Option<SortKey>,
+    pub sort_reverse: bool,
+    // --files: print the paths that would be searched instead of searching them
+    pub list_files: bool,
+    // -r only searches files matching some include (if any are given) and no exclude
+    pub include_globs: Vec<String>,
+    pub exclude_globs: Vec<String>,
+    // -t: -r only searches files with an extension belonging to one of these FILE_TYPES
+    pub types: Vec<String>,
+    // How deep -r descends, the starting directory being depth 0; None means no limit
+    pub max_depth: Option<usize>,
+    // -r skips files larger than this many bytes
+    pub max_filesize: Option<u64>,
+    // -a: search binary files like any other instead of only reporting whether they match
+    pub text: bool,
+    // --binary: search binary files as raw bytes and report where each match starts
+    pub binary: bool,
+    // --search-zip: .gz files are decompressed as they are read
+    pub search_zip: bool,
+    // --pre: a command whose output for each file is searched in place of the file
+    pub pre: Option<String>,
+    // -z: lines end with NUL rather than newline, both when reading and when printing
+    pub null_data: bool,
+    // -Z: a NUL byte follows each printed filename, for xargs -0
+    pub null_separator: bool,
+    // -b: prefix each printed line with the byte offset of its start (of the match with -o)
+    pub byte_offset: bool,
+    // --trim: print lines without their leading and trailing whitespace
+    pub trim: bool,
+    // --field-separator: replaces ": " between the fields of a selected line
+    pub field_separator: Option<String>,
+    // --path-separator: printed paths use this in place of the platform's separators
+    pub path_separator: Option<char>,
+    // --max-columns: longer printed lines are cut down to this many characters, around the
+    // first match with --max-columns-preview
+    pub max_columns: Option<usize>,
+    pub max_columns_preview: bool,
     pub print_filenames: bool,
+    // --no-filename, which wins over -f and the automatic prefix
+    pub no_filename: bool,
+    // Print the path once above each file's lines instead of on every line
+    pub heading: bool,
+    // One file:line:column:text row per match, for Vim's 'grepprg'
+    pub vimgrep: bool,
+    // JSON Lines output, one object per selected line
+    pub json: bool,
     pub colored_output :bool,
+    pub use_regex: bool,
+    // -F: treat the patterns as literal text, overriding -E
+    pub fixed_strings: bool,
+    pub after_context: usize,
+    pub before_context: usize,
+    // Printed between non-adjacent groups of context; None leaves nothing between them
+    pub group_separator: Option<String>,
+    pub only_matching: bool,
+    // --o-separator: -o joins a line's matches with this instead of printing one per line
+    pub o_separator: Option<String>,
+    pub count_only: bool,
+    // Like count_only, but counting every occurrence rather than every line
+    pub count_matches: bool,
+    // --total: after the per-file counts, print their sum across every file searched
+    pub total: bool,
+    pub max_count: Option<usize>,
+    // --max-matches: like max_count, but for the whole run rather than each file
+    pub max_total_matches: Option<usize>,
+    pub files_with_matches: bool,
+    pub files_without_matches: bool,
+    pub quiet: bool,
+    pub no_messages: bool,
+    pub word_match: bool,
+    pub line_match: bool,
+    pub all_match: bool,
+    // --and-not: a line that also contains this is not a match
+    pub exclude_pattern: Option<String>,
+    // --fuzzy: the Levenshtein distance within which some part of a line matches a query
+    pub fuzzy: Option<usize>,
+    // --multiline: run the -E pattern over the whole file instead of line by line
+    pub multiline: bool,
+    // --dotall: . also matches "\n"
+    pub dotall: bool,
+    // --multiline-regex: ^ and $ match at line breaks within the text searched. Lines are
+    // normally searched one at a time, where they match at the ends of each line anyway, so
+    // this only matters with --multiline
+    pub multiline_regex: bool,
+    pub smart_case: bool,
+    // Worker threads for recursive search; None lets rayon decide and 1 searches serially
+    pub threads: Option<usize>,
+    pub match_color: Color,
+    // Colors of the other fields when coloring, after GNU grep's defaults
+    pub path_color: Color,
+    pub line_number_color: Color,
+    pub separator_color: Color,
+    // --replace: text printed in place of each match on selected lines
+    pub replacement: Option<String>,
+    // --in-place: write the replacements back into the files. The string is the backup
+    // suffix, empty when no backup is kept
+    pub in_place: Option<String>,
+    // --stats: report totals on stderr once the search is done
+    pub stats: bool,
 }
 
-impl Config {
-    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
-        args.next(); // Skip the program name
+// What --sort orders the files found by -r by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Path,
+    Modified,
+    Size,
+}
+
+// Everything that can go wrong while parsing the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    MissingQuery,
+    MissingFiles,
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    UnreadablePatternsFile(String, String),
+    UnreadableFilesList(String, String),
+    // The first option only makes sense together with the second
+    RequiresOption(String, String),
+    // The two options ask for different things and can't both be honored
+    ConflictingOptions(String, String),
+    // Not a real error: carries the usage text (or the --type-list table) so the caller can
+    // print it
+    HelpRequested(String),
+}
 
-        let query = match args.next() {
-            Some(arg) if arg == "-h" || arg == "--help" => {
-                return Err(
-                    "Usage: grep [OPTIONS] <pattern> <files...>\nOptions:\n-i\tCase-insensitive search\n-n\tPrint line numbers\n-v\tInvert match (exclude lines that match the pattern)\n-r\tRecursive directory search\n-f\tPrint filenames\n-c\tEnable colored output\n-h, --help\tShow help information",
-                );
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingQuery => write!(f, "Didn't get a query string"),
+            ConfigError::MissingFiles => write!(f, "Didn't get any file paths"),
+            ConfigError::UnknownOption(option) => write!(f, "Unknown option encountered: {}", option),
+            ConfigError::MissingValue(option) => write!(f, "Option {} requires a value", option),
+            ConfigError::InvalidValue(option, value) => {
+                write!(f, "Invalid value '{}' for option {}", value, option)
             }
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
+            ConfigError::UnreadablePatternsFile(path, reason) => {
+                write!(f, "Could not read patterns file {}: {}", path, reason)
+            }
+            ConfigError::UnreadableFilesList(path, reason) => {
+                write!(f, "Could not read file list {}: {}", path, reason)
+            }
+            ConfigError::RequiresOption(option, required) => {
+                write!(f, "Option {} requires {}", option, required)
+            }
+            ConfigError::ConflictingOptions(first, second) => {
+                write!(f, "Options {} and {} can't be used together", first, second)
+            }
+            ConfigError::HelpRequested(usage) => write!(f, "{}", usage),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    // Like build, but for arguments without a program name in front, which is handier when
+    // embedding: Config::build_from_slice(&["-n", "needle", "src"])
+    pub fn build_from_slice(args: &[&str]) -> Result<Config, ConfigError> {
+        let program = std::iter::once(String::new());
+        Config::build(program.chain(args.iter().map(|arg| arg.to_string())))
+    }
+    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, ConfigError> {
+        args.next(); // Skip the program name
 
         // Set default values for options
         let mut case_insensitive = false;
         let mut line_number = false;
+        let mut line_number_width = None;
         let mut invert_match = false;
         let mut recursive_search = false;
+        let mut respect_ignore = true;
+        let mut hidden = false;
+        let mut follow_links = false;
+        let mut sort = None;
+        let mut sort_reverse = false;
+        let mut list_files = false;
+        let mut include_globs = Vec::new();
+        let mut exclude_globs = Vec::new();
+        let mut types = Vec::new();
+        let mut max_depth = None;
+        let mut max_filesize = None;
+        let mut text = false;
+        let mut binary = false;
+        let mut search_zip = false;
+        let mut pre = None;
+        let mut null_data = false;
+        let mut null_separator = false;
+        let mut byte_offset = false;
+        let mut trim = false;
+        let mut field_separator = None;
+        let mut max_columns = None;
+        let mut max_columns_preview = false;
         let mut print_filenames = false;
-        let mut colored_output = false;
+        let mut no_filename = false;
+        let mut heading = false;
+        let mut vimgrep = false;
+        let mut json = false;
+        // Color defaults to auto, so escape codes never end up in a file or a pipe
+        let mut colored_output = io::stdout().is_terminal();
+        let mut use_regex = false;
+        let mut fixed_strings = false;
+        let mut after_context = 0;
+        let mut before_context = 0;
+        let mut group_separator = Some("--".to_string());
+        let mut only_matching = false;
+        let mut o_separator = None;
+        let mut path_separator = None;
+        let mut count_only = false;
+        let mut count_matches = false;
+        let mut total = false;
+        let mut max_count = None;
+        let mut max_total_matches = None;
+        let mut files_with_matches = false;
+        let mut files_without_matches = false;
+        let mut quiet = false;
+        let mut no_messages = false;
+        let mut word_match = false;
+        let mut line_match = false;
+        let mut smart_case = false;
+        let mut all_match = false;
+        let mut exclude_pattern = None;
+        let mut fuzzy = None;
+        let mut multiline = false;
+        let mut dotall = false;
+        let mut multiline_regex = false;
+        let mut threads = None;
+        let mut match_color = Color::Red;
+        let mut path_color = Color::Magenta;
+        let mut line_number_color = Color::Green;
+        let mut separator_color = Color::Cyan;
+        // GREP_COLORS replaces the defaults, and the --color-* options below replace it
+        if let Ok(spec) = env::var("GREP_COLORS") {
+            for (key, color) in parse_grep_colors(&spec) {
+                match key {
+                    "ms" | "mt" => match_color = color,
+                    "fn" => path_color = color,
+                    "ln" | "bn" => line_number_color = color,
+                    "se" => separator_color = color,
+                    _ => {}
+                }
+            }
+        }
+        let mut replacement = None;
+        let mut in_place = None;
+        let mut stats = false;
+        let mut queries = Vec::new();
+        // Paths read by --files-from, searched after the ones on the command line
+        let mut listed_files: Vec<String> = Vec::new();
+        let mut files_listed = false;
+        // Set once -e or --patterns-file supplies patterns, even if the file had none
+        let mut patterns_given = false;
 
-        // A vector to hold all the file paths
-        let mut file_paths: Vec<PathBuf> = Vec::new();
+        // Non-option arguments: the pattern (unless -e is used) followed by the file paths
+        let mut positionals = Vec::new();
 
         // Parse the remaining arguments
-        while let Some(arg) = args.next() {
+        let mut args = ArgStream { args, pending: VecDeque::new() };
+        while let Some(arg) = args.next_arg() {
             if arg.starts_with('-') {
                 // Handle options
                 match arg.as_str() {
-                    "-i" => case_insensitive = true,
-                    "-n" => line_number = true,
-                    "-v" => invert_match = true,
-                    "-r" => recursive_search = true,
-                    "-f" => print_filenames = true,
-                    "-c" => colored_output = true,
-                    "-h" | "--help" => {
-                        return Err(
-                            "Usage: grep [OPTIONS] <pattern> <files...>\nOptions:\n-i\tCase-insensitive search\n-n\tPrint line numbers\n-v\tInvert match (exclude lines that match the pattern)\n-r\tRecursive directory search\n-f\tPrint filenames\n-c\tEnable colored output\n-h, --help\tShow help information",
+                    "-i" | "--ignore-case" => case_insensitive = true,
+                    "-n" | "--line-number" => line_number = true,
+                    "--line-number-width" => line_number_width = Some(0),
+                    _ if arg.starts_with("--line-number-width=") => {
+                        let value = arg["--line-number-width=".len()..].to_string();
+                        line_number_width = Some(parse_number("--line-number-width", Some(value))?);
+                    }
+                    "-v" | "--invert-match" => invert_match = true,
+                    "-r" | "--recursive" => recursive_search = true,
+                    "--gitignore" => respect_ignore = true,
+                    "--no-ignore" => respect_ignore = false,
+                    "--hidden" => hidden = true,
+                    "--follow" => follow_links = true,
+                    "--sort" | "--sortr" => {
+                        let value = args
+                            .next_value()
+                            .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                        sort = Some(parse_sort_key(&arg, value)?);
+                        sort_reverse = arg == "--sortr";
+                    }
+                    _ if arg.starts_with("--sort=") || arg.starts_with("--sortr=") => {
+                        let (option, value) = arg.split_once('=').unwrap_or_default();
+                        sort = Some(parse_sort_key(option, value.to_string())?);
+                        sort_reverse = option == "--sortr";
+                    }
+                    "--files" => {
+                        list_files = true;
+                        recursive_search = true;
+                    }
+                    "--include" => include_globs.push(parse_glob(&arg, args.next_value())?),
+                    "--exclude" => exclude_globs.push(parse_glob(&arg, args.next_value())?),
+                    "-t" | "--type" => types.push(parse_type(&arg, args.next_value())?),
+                    "--type-list" => return Err(ConfigError::HelpRequested(type_list())),
+                    "--max-depth" => max_depth = Some(parse_number(&arg, args.next_value())?),
+                    "--max-filesize" => max_filesize = Some(parse_size(&arg, args.next_value())?),
+                    "-a" | "--text" => text = true,
+                    "--binary" => binary = true,
+                    "--search-zip" => search_zip = true,
+                    "--pre" => {
+                        pre = Some(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
+                        );
+                    }
+                    "-z" | "--null-data" => null_data = true,
+                    "-Z" | "--null" => null_separator = true,
+                    "-b" | "--byte-offset" => byte_offset = true,
+                    "--trim" => trim = true,
+                    "--field-separator" => {
+                        field_separator = Some(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
+                        );
+                    }
+                    "--max-columns" => max_columns = Some(parse_number(&arg, args.next_value())?),
+                    "--max-columns-preview" => max_columns_preview = true,
+                    "-f" | "--with-filename" => print_filenames = true,
+                    "--no-filename" => no_filename = true,
+                    "--heading" => heading = true,
+                    "--vimgrep" => vimgrep = true,
+                    "--json" => json = true,
+                    "-c" | "--color" | "--color=always" => colored_output = true,
+                    "--color=never" => colored_output = false,
+                    // Only color when a person is reading the output
+                    "--color=auto" => colored_output = io::stdout().is_terminal(),
+                    _ if arg.starts_with("--color=") => {
+                        let value = arg["--color=".len()..].to_string();
+                        return Err(ConfigError::InvalidValue("--color".to_string(), value));
+                    }
+                    "-E" | "--regex" => use_regex = true,
+                    "-F" | "--fixed-strings" => fixed_strings = true,
+                    "-o" | "--only-matching" => only_matching = true,
+                    "--o-separator" => {
+                        o_separator = Some(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
+                        );
+                    }
+                    "--path-separator" => {
+                        let value = args
+                            .next_value()
+                            .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                        let mut chars = value.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(separator), None) => path_separator = Some(separator),
+                            _ => return Err(ConfigError::InvalidValue(arg.clone(), value)),
+                        }
+                    }
+                    // -c already means colored output, so counting is long-form only
+                    "--count" => count_only = true,
+                    "--only-count-matches" | "--count-matches" => count_matches = true,
+                    "--total" => total = true,
+                    "-m" | "--max-count" => {
+                        max_count = Some(parse_number(&arg, args.next_value())?);
+                    }
+                    "--max-matches" => {
+                        max_total_matches = Some(parse_number(&arg, args.next_value())?);
+                    }
+                    "-l" => files_with_matches = true,
+                    "-L" => files_without_matches = true,
+                    "-q" | "--quiet" => quiet = true,
+                    "-s" | "--no-messages" => no_messages = true,
+                    "-w" => word_match = true,
+                    "-x" => line_match = true,
+                    "--smart-case" => smart_case = true,
+                    "--all-match" => all_match = true,
+                    "--fuzzy" => fuzzy = Some(parse_number(&arg, args.next_value())?),
+                    "--multiline" => multiline = true,
+                    "--dotall" => dotall = true,
+                    "--multiline-regex" => multiline_regex = true,
+                    "--and-not" => {
+                        exclude_pattern = Some(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
+                        );
+                    }
+                    "--threads" => threads = Some(parse_number(&arg, args.next_value())?),
+                    "--color-match" => match_color = parse_color(&arg, args.next_value())?,
+                    "--color-path" => path_color = parse_color(&arg, args.next_value())?,
+                    "--color-line" => line_number_color = parse_color(&arg, args.next_value())?,
+                    "--color-separator" => {
+                        separator_color = parse_color(&arg, args.next_value())?;
+                    }
+                    "--stats" => stats = true,
+                    "--in-place" => in_place = Some(String::new()),
+                    _ if arg.starts_with("--in-place=") => {
+                        in_place = Some(arg["--in-place=".len()..].to_string());
+                    }
+                    "--replace" => {
+                        replacement = Some(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
+                        );
+                    }
+                    // -e always consumes the next argument, even one starting with '-'
+                    "-e" => {
+                        queries.push(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
+                        );
+                        patterns_given = true;
+                    }
+                    "--patterns-file" => {
+                        let path = args
+                            .next_value()
+                            .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                        queries.extend(read_patterns_file(&path)?);
+                        patterns_given = true;
+                    }
+                    "--files-from" => {
+                        let path = args
+                            .next_value()
+                            .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                        listed_files.extend(read_files_list(&path)?);
+                        files_listed = true;
+                    }
+                    "--group-separator" => {
+                        group_separator = Some(
+                            args.next_value()
+                                .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?,
                         );
                     }
-                    _ => return Err("Unknown option encountered"),
+                    "--no-group-separator" => group_separator = None,
+                    "-A" => after_context = parse_number(&arg, args.next_value())?,
+                    "-B" => before_context = parse_number(&arg, args.next_value())?,
+                    // Whichever of -A/-B/-C comes last decides each side, like grep
+                    "-C" => {
+                        let context = parse_number(&arg, args.next_value())?;
+                        after_context = context;
+                        before_context = context;
+                    }
+                    "-h" | "--help" => {
+                        return Err(ConfigError::HelpRequested(USAGE.to_string()));
+                    }
+                    _ => return Err(ConfigError::UnknownOption(arg)),
                 }
             } else {
-                // Handle file paths and wildcards
-                if arg.contains('*') {
-                    // Handle wildcard expansion using glob for patterns like *.md
-                    match glob(&arg) {
-                        Ok(paths) => {
-                            for path in paths {
-                                match path {
-                                    Ok(path_buf) => file_paths.push(path_buf),
-                                    Err(e) => eprintln!("Error reading path: {:?}", e),
-                                }
+                positionals.push(arg);
+            }
+        }
+
+        // The first positional argument is the pattern unless -e or --patterns-file was used,
+        // or --files, which matches nothing
+        let mut positionals = positionals.into_iter();
+        if !patterns_given && !list_files {
+            queries.push(positionals.next().ok_or(ConfigError::MissingQuery)?);
+        }
+
+        // Smart case ignores case only for all-lowercase patterns; an explicit -i always wins
+        if smart_case && !case_insensitive {
+            case_insensitive = !queries.iter().any(|query| query.chars().any(char::is_uppercase));
+        }
+
+        // Paths that were asked for but came to nothing must not fall back to a default
+        let paths_given = positionals.len() > 0 || files_listed;
+
+        // A vector to hold all the file paths
+        let mut file_paths: Vec<PathBuf> = Vec::new();
+        for arg in positionals {
+            let arg = expand_tilde(arg);
+            // Handle file paths and wildcards
+            if arg.contains('*') {
+                // Handle wildcard expansion using glob for patterns like *.md, where ** also
+                // matches any number of directories, as in src/**/*.rs
+                match glob(&arg) {
+                    Ok(paths) => {
+                        let expanded_from = file_paths.len();
+                        for path in paths {
+                            match path {
+                                // Directories a wildcard happens to match are only walked
+                                // under -r; otherwise they're left out
+                                Ok(path_buf) if path_buf.is_dir() && !recursive_search => {}
+                                Ok(path_buf) => file_paths.push(path_buf),
+                                Err(e) => eprintln!("Error reading path: {:?}", e),
                             }
                         }
-                        Err(e) => eprintln!("Failed to read glob pattern {}: {}", arg, e),
+                        // Otherwise a mistyped extension would quietly search nothing
+                        if file_paths.len() == expanded_from && !no_messages {
+                            eprintln!("grep: no matches for pattern '{}'", arg);
+                        }
                     }
-                } else {
-                    // Preserve relative paths (like "../") 
-                    file_paths.push(PathBuf::from(arg));
+                    Err(e) => eprintln!("Failed to read glob pattern {}: {}", arg, e),
                 }
+            } else {
+                // Preserve relative paths (like "../") 
+                file_paths.push(PathBuf::from(arg));
             }
         }
+        // Listed paths are taken as they are: the tool that wrote the list already expanded
+        // any wildcards
+        file_paths.extend(listed_files.into_iter().map(PathBuf::from));
+
+        // Search a file named twice (or hit by overlapping globs) only once, where it first
+        // appeared. Paths that don't exist can't be canonicalized and are compared as given
+        let mut seen = HashSet::new();
+        file_paths.retain(|path| {
+            seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+        });
 
-        // Ensure at least one file is provided
-        if file_paths.is_empty() {
-            return Err("Didn't get any file paths");
+        // A glob that matched nothing or an empty --files-from list searches nothing rather
+        // than widening to the current directory or standard input
+        if paths_given && file_paths.is_empty() {
+            return Err(ConfigError::MissingFiles);
+        }
+        // Without any files -r searches the current directory, like GNU grep, and otherwise
+        // we read standard input, which can't be rewritten in place
+        if file_paths.is_empty() && recursive_search {
+            file_paths.push(PathBuf::from("."));
+        }
+        if file_paths.is_empty() && in_place.is_some() {
+            let required = "files to edit (standard input can't be rewritten)".to_string();
+            return Err(ConfigError::RequiresOption("--in-place".to_string(), required));
+        }
+        // There is nothing to walk below plain files, so `-r file.txt` searches just like
+        // `file.txt` does, without a filename prefix for a lone file. A path that doesn't
+        // exist counts as a file here, so it's reported the same way with or without -r
+        if recursive_search && file_paths.iter().all(|path| !path.is_dir()) {
+            recursive_search = false;
+        }
+        if in_place.is_some() && replacement.is_none() {
+            let (option, required) = ("--in-place".to_string(), "--replace".to_string());
+            return Err(ConfigError::RequiresOption(option, required));
+        }
+        // -v selects the lines without a match, which leaves nothing to replace in them
+        if in_place.is_some() && invert_match {
+            let (first, second) = ("--in-place".to_string(), "-v".to_string());
+            return Err(ConfigError::ConflictingOptions(first, second));
+        }
+        // The edit would replace a .gz file with its decompressed text
+        if in_place.is_some() && search_zip {
+            let (first, second) = ("--in-place".to_string(), "--search-zip".to_string());
+            return Err(ConfigError::ConflictingOptions(first, second));
+        }
+        // Likewise a file with what the --pre command printed for it
+        if in_place.is_some() && pre.is_some() {
+            let (first, second) = ("--in-place".to_string(), "--pre".to_string());
+            return Err(ConfigError::ConflictingOptions(first, second));
+        }
+        // Only a regex can say where a match crosses a line break
+        if multiline && (!use_regex || fixed_strings) {
+            let (option, required) = ("--multiline".to_string(), "-E".to_string());
+            return Err(ConfigError::RequiresOption(option, required));
+        }
+        if max_columns_preview && max_columns.is_none() {
+            let option = "--max-columns-preview".to_string();
+            return Err(ConfigError::RequiresOption(option, "--max-columns".to_string()));
+        }
+        // --count counts lines and --count-matches every occurrence; rather than pick one
+        // meaning of "count" silently, refuse to guess
+        if count_only && count_matches {
+            let (first, second) = ("--count".to_string(), "--count-matches".to_string());
+            return Err(ConfigError::ConflictingOptions(first, second));
+        }
+        if total && !(count_only || count_matches) {
+            let option = "--total".to_string();
+            let required = "--count or --count-matches".to_string();
+            return Err(ConfigError::RequiresOption(option, required));
         }
 
         // Return the constructed Config object
         Ok(Config {
-            query,
+            queries,
             file_paths,
             case_insensitive,
             line_number,
+            line_number_width,
             invert_match,
             recursive_search,
+            respect_ignore,
+            hidden,
+            follow_links,
+            sort,
+            sort_reverse,
+            list_files,
+            include_globs,
+            exclude_globs,
+            types,
+            max_depth,
+            max_filesize,
+            text,
+            binary,
+            search_zip,
+            pre,
+            null_data,
+            null_separator,
+            byte_offset,
+            trim,
+            field_separator,
+            max_columns,
+            max_columns_preview,
             print_filenames,
+            no_filename,
+            heading,
+            vimgrep,
+            json,
             colored_output,
+            use_regex,
+            fixed_strings,
+            after_context,
+            before_context,
+            group_separator,
+            only_matching,
+            o_separator,
+            path_separator,
+            count_only,
+            count_matches,
+            total,
+            max_count,
+            max_total_matches,
+            files_with_matches,
+            files_without_matches,
+            quiet,
+            no_messages,
+            word_match,
+            line_match,
+            all_match,
+            exclude_pattern,
+            fuzzy,
+            multiline,
+            dotall,
+            multiline_regex,
+            smart_case,
+            threads,
+            match_color,
+            path_color,
+            line_number_color,
+            separator_color,
+            replacement,
+            in_place,
+            stats,
+        })
+    }
+}
+
+// The CLI's defaults: no patterns, no files (so standard input), every option off and red
+// highlights. Color stays off here rather than guessing about a terminal
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            queries: Vec::new(),
+            file_paths: Vec::new(),
+            case_insensitive: false,
+            line_number: false,
+            line_number_width: None,
+            invert_match: false,
+            recursive_search: false,
+            respect_ignore: true,
+            hidden: false,
+            follow_links: false,
+            sort: None,
+            sort_reverse: false,
+            list_files: false,
+            include_globs: Vec::new(),
+            exclude_globs: Vec::new(),
+            types: Vec::new(),
+            max_depth: None,
+            max_filesize: None,
+            text: false,
+            binary: false,
+            search_zip: false,
+            pre: None,
+            null_data: false,
+            null_separator: false,
+            byte_offset: false,
+            trim: false,
+            field_separator: None,
+            max_columns: None,
+            max_columns_preview: false,
+            print_filenames: false,
+            no_filename: false,
+            heading: false,
+            vimgrep: false,
+            json: false,
+            colored_output: false,
+            use_regex: false,
+            fixed_strings: false,
+            after_context: 0,
+            before_context: 0,
+            group_separator: Some("--".to_string()),
+            only_matching: false,
+            o_separator: None,
+            path_separator: None,
+            count_only: false,
+            count_matches: false,
+            total: false,
+            max_count: None,
+            max_total_matches: None,
+            files_with_matches: false,
+            files_without_matches: false,
+            quiet: false,
+            no_messages: false,
+            word_match: false,
+            line_match: false,
+            all_match: false,
+            exclude_pattern: None,
+            fuzzy: None,
+            multiline: false,
+            dotall: false,
+            multiline_regex: false,
+            smart_case: false,
+            threads: None,
+            match_color: Color::Red,
+            path_color: Color::Magenta,
+            line_number_color: Color::Green,
+            separator_color: Color::Cyan,
+            replacement: None,
+            in_place: None,
+            stats: false,
+        }
+    }
+}
+
+/// Builds a Config for library use without going through command-line arguments, to hand
+/// to search_matches
+///
+/// ```
+/// use grep::{search_matches, ConfigBuilder};
+///
+/// let dir = std::env::temp_dir().join(format!("grep-doctest-{}", std::process::id()));
+/// std::fs::create_dir_all(dir.join("src")).unwrap();
+/// std::fs::write(dir.join("src/notes.txt"), "hay\nA Needle\nmore hay\n").unwrap();
+///
+/// let config = ConfigBuilder::new("needle")
+///     .case_insensitive(true)
+///     .recursive(true)
+///     .file(dir.join("src"))
+///     .build();
+/// let matches = search_matches(&config).unwrap();
+/// assert_eq!(matches.len(), 1);
+/// assert_eq!(matches[0].line_number, 2);
+/// assert_eq!(matches[0].line, "A Needle");
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new(query: impl Into<String>) -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config {
+                queries: vec![query.into()],
+                ..Config::default()
+            },
+        }
+    }
+    // Another pattern; a line matches if it contains any of them
+    pub fn query(mut self, query: impl Into<String>) -> ConfigBuilder {
+        self.config.queries.push(query.into());
+        self
+    }
+    pub fn file(mut self, path: impl Into<PathBuf>) -> ConfigBuilder {
+        self.config.file_paths.push(path.into());
+        self
+    }
+    pub fn case_insensitive(mut self, yes: bool) -> ConfigBuilder {
+        self.config.case_insensitive = yes;
+        self
+    }
+    pub fn line_number(mut self, yes: bool) -> ConfigBuilder {
+        self.config.line_number = yes;
+        self
+    }
+    pub fn invert_match(mut self, yes: bool) -> ConfigBuilder {
+        self.config.invert_match = yes;
+        self
+    }
+    pub fn recursive(mut self, yes: bool) -> ConfigBuilder {
+        self.config.recursive_search = yes;
+        self
+    }
+    pub fn respect_ignore(mut self, yes: bool) -> ConfigBuilder {
+        self.config.respect_ignore = yes;
+        self
+    }
+    pub fn hidden(mut self, yes: bool) -> ConfigBuilder {
+        self.config.hidden = yes;
+        self
+    }
+    pub fn follow_links(mut self, yes: bool) -> ConfigBuilder {
+        self.config.follow_links = yes;
+        self
+    }
+    pub fn include(mut self, glob: impl Into<String>) -> ConfigBuilder {
+        self.config.include_globs.push(glob.into());
+        self
+    }
+    pub fn exclude(mut self, glob: impl Into<String>) -> ConfigBuilder {
+        self.config.exclude_globs.push(glob.into());
+        self
+    }
+    // Unlike -t this doesn't check the name; an unknown type simply matches no files
+    pub fn file_type(mut self, name: impl Into<String>) -> ConfigBuilder {
+        self.config.types.push(name.into());
+        self
+    }
+    pub fn max_depth(mut self, depth: Option<usize>) -> ConfigBuilder {
+        self.config.max_depth = depth;
+        self
+    }
+    pub fn search_zip(mut self, yes: bool) -> ConfigBuilder {
+        self.config.search_zip = yes;
+        self
+    }
+    pub fn text(mut self, yes: bool) -> ConfigBuilder {
+        self.config.text = yes;
+        self
+    }
+    pub fn with_filename(mut self, yes: bool) -> ConfigBuilder {
+        self.config.print_filenames = yes;
+        self
+    }
+    pub fn color(mut self, yes: bool) -> ConfigBuilder {
+        self.config.colored_output = yes;
+        self
+    }
+    pub fn regex(mut self, yes: bool) -> ConfigBuilder {
+        self.config.use_regex = yes;
+        self
+    }
+    pub fn fixed_strings(mut self, yes: bool) -> ConfigBuilder {
+        self.config.fixed_strings = yes;
+        self
+    }
+    pub fn word_match(mut self, yes: bool) -> ConfigBuilder {
+        self.config.word_match = yes;
+        self
+    }
+    pub fn line_match(mut self, yes: bool) -> ConfigBuilder {
+        self.config.line_match = yes;
+        self
+    }
+    pub fn context(mut self, before: usize, after: usize) -> ConfigBuilder {
+        self.config.before_context = before;
+        self.config.after_context = after;
+        self
+    }
+    pub fn max_count(mut self, max: Option<usize>) -> ConfigBuilder {
+        self.config.max_count = max;
+        self
+    }
+    pub fn threads(mut self, threads: Option<usize>) -> ConfigBuilder {
+        self.config.threads = threads;
+        self
+    }
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+// Short options that take a value, which ends a cluster like -inA3
+const OPTIONS_WITH_VALUES: [char; 6] = ['A', 'B', 'C', 'm', 'e', 't'];
+
+// The file types -t understands and the extensions belonging to each
+const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["c", "h"]),
+    ("cpp", &["cpp", "cc", "cxx", "hpp", "hh", "hxx"]),
+    ("css", &["css"]),
+    ("go", &["go"]),
+    ("html", &["html", "htm"]),
+    ("java", &["java"]),
+    ("js", &["js", "mjs", "cjs"]),
+    ("json", &["json"]),
+    ("markdown", &["md", "markdown"]),
+    ("py", &["py", "pyi"]),
+    ("rust", &["rs"]),
+    ("sh", &["sh", "bash"]),
+    ("toml", &["toml"]),
+    ("ts", &["ts", "tsx"]),
+    ("txt", &["txt"]),
+    ("yaml", &["yaml", "yml"]),
+];
+
+// Command-line arguments, with clusters of short options such as -in split into -i -n
+struct ArgStream<I: Iterator<Item = String>> {
+    args: I,
+    // Options (and possibly a value) left over from the cluster being split
+    pending: VecDeque<String>,
+}
+
+impl<I: Iterator<Item = String>> ArgStream<I> {
+    // The next option or positional argument
+    fn next_arg(&mut self) -> Option<String> {
+        if let Some(arg) = self.pending.pop_front() {
+            return Some(arg);
+        }
+        let arg = self.args.next()?;
+        if !arg.starts_with('-') || arg.starts_with("--") || arg.chars().count() <= 2 {
+            return Some(arg);
+        }
+
+        for (index, letter) in arg.char_indices().skip(1) {
+            self.pending.push_back(format!("-{}", letter));
+            // An option taking a value consumes the rest of the cluster, as in -A3
+            if OPTIONS_WITH_VALUES.contains(&letter) {
+                let rest = &arg[index + letter.len_utf8()..];
+                if !rest.is_empty() {
+                    self.pending.push_back(rest.to_string());
+                }
+                break;
+            }
+        }
+        self.pending.pop_front()
+    }
+
+    // The value of an option: the rest of its cluster, or else the next argument as-is
+    fn next_value(&mut self) -> Option<String> {
+        self.pending.pop_front().or_else(|| self.args.next())
+    }
+}
+
+// Load --patterns-file, one pattern per line. Blank lines are skipped, so an empty file
+// contributes no patterns and, like grep, matches nothing
+fn read_patterns_file(path: &str) -> Result<Vec<String>, ConfigError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| ConfigError::UnreadablePatternsFile(path.to_string(), describe_io_error(&e)))?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// Load --files-from, one path per line, from standard input when PATH is -. Blank lines
+// and lines starting with # are skipped
+fn read_files_list(path: &str) -> Result<Vec<String>, ConfigError> {
+    let contents = if path == "-" {
+        io::read_to_string(io::stdin())
+    } else {
+        fs::read_to_string(path)
+    };
+    let contents = contents
+        .map_err(|e| ConfigError::UnreadableFilesList(path.to_string(), describe_io_error(&e)))?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+// Read the key given to --sort or --sortr
+fn parse_sort_key(option: &str, value: String) -> Result<SortKey, ConfigError> {
+    match value.as_str() {
+        "path" => Ok(SortKey::Path),
+        "modified" => Ok(SortKey::Modified),
+        "size" => Ok(SortKey::Size),
+        _ => Err(ConfigError::InvalidValue(option.to_string(), value)),
+    }
+}
+
+// Read the numeric value that follows an option such as -A
+fn parse_number(option: &str, value: Option<String>) -> Result<usize, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    value
+        .parse()
+        .map_err(|_| ConfigError::InvalidValue(option.to_string(), value))
+}
+// Expand a leading ~ (alone or followed by /) to the home directory, for paths that reach
+// us without going through a shell. A ~ anywhere else, or ~user, is left alone
+fn expand_tilde(arg: String) -> String {
+    let rest = match arg.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return arg,
+    };
+    match env::home_dir() {
+        Some(home) => format!("{}{}", home.display(), rest),
+        None => arg,
+    }
+}
+// Parse a size such as 500, 500K, 10M or 2G, the suffixes being powers of 1024
+fn parse_size(option: &str, value: Option<String>) -> Result<u64, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&value[..at], 1 << 10),
+        Some((at, 'M' | 'm')) => (&value[..at], 1 << 20),
+        Some((at, 'G' | 'g')) => (&value[..at], 1 << 30),
+        _ => (value.as_str(), 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| ConfigError::InvalidValue(option.to_string(), value.clone()))
+}
+// Accept only the type names listed in FILE_TYPES
+fn parse_type(option: &str, value: Option<String>) -> Result<String, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    if FILE_TYPES.iter().any(|&(name, _)| name == value) {
+        Ok(value)
+    } else {
+        Err(ConfigError::InvalidValue(option.to_string(), value))
+    }
+}
+// The --type-list table, one "name: *.ext, *.ext" line per type
+fn type_list() -> String {
+    FILE_TYPES
+        .iter()
+        .map(|(name, extensions)| {
+            let globs: Vec<String> = extensions.iter().map(|ext| format!("*.{}", ext)).collect();
+            format!("{}: {}", name, globs.join(", "))
         })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+// Check that an --include/--exclude glob compiles, so a typo fails up front
+fn parse_glob(option: &str, value: Option<String>) -> Result<String, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    match Pattern::new(&value) {
+        Ok(_) => Ok(value),
+        Err(_) => Err(ConfigError::InvalidValue(option.to_string(), value)),
+    }
+}
+// Parse a color name the `colored` crate knows; its `From<&str>` would quietly turn a typo
+// into white, so go through `FromStr` and report the bad name instead
+fn parse_color(option: &str, value: Option<String>) -> Result<Color, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    value
+        .parse()
+        .map_err(|_| ConfigError::InvalidValue(option.to_string(), value))
+}
+// Read a GREP_COLORS spec such as "ms=01;31:fn=35:ln=32" into the colors it sets. Entries
+// without a value (like "ne"), with an unknown key, or without a foreground color we can
+// show are skipped, so a partial or partly malformed spec still applies what it can
+fn parse_grep_colors(spec: &str) -> Vec<(&str, Color)> {
+    spec.split(':')
+        .filter_map(|entry| entry.split_once('='))
+        .filter_map(|(key, sgr)| Some((key, sgr_color(sgr)?)))
+        .collect()
+}
+// The foreground color an SGR sequence like "01;31" selects. Attributes such as bold are
+// ignored; 38;2;R;G;B is a 24-bit color and 38;5;N a 256-color one, which is only
+// understood for N < 16
+fn sgr_color(sgr: &str) -> Option<Color> {
+    const BASIC: [Color; 8] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::White,
+    ];
+    const BRIGHT: [Color; 8] = [
+        Color::BrightBlack,
+        Color::BrightRed,
+        Color::BrightGreen,
+        Color::BrightYellow,
+        Color::BrightBlue,
+        Color::BrightMagenta,
+        Color::BrightCyan,
+        Color::BrightWhite,
+    ];
+    let codes = sgr
+        .split(';')
+        .map(|code| code.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let mut color = None;
+    let mut codes = codes.into_iter();
+    while let Some(code) = codes.next() {
+        match code {
+            30..=37 => color = Some(BASIC[usize::from(code - 30)]),
+            90..=97 => color = Some(BRIGHT[usize::from(code - 90)]),
+            38 => match (codes.next(), codes.next()) {
+                (Some(2), Some(r)) => {
+                    let (g, b) = (codes.next()?, codes.next()?);
+                    color = Some(Color::TrueColor { r, g, b });
+                }
+                (Some(5), Some(n @ 0..=7)) => color = Some(BASIC[usize::from(n)]),
+                (Some(5), Some(n @ 8..=15)) => color = Some(BRIGHT[usize::from(n - 8)]),
+                _ => return None,
+            },
+            _ => {}
+        }
+    }
+    color
+}
+
+// A single selected line, with the file and 1-based line number it came from. This is also
+// what --json prints
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Match {
+    #[serde(rename = "path")]
+    pub file: PathBuf,
+    pub line_number: usize,
+    pub line: String,
+    // Where each occurrence of a query sits in `line`; empty for an inverted match
+    pub submatches: Vec<Submatch>,
+}
+
+// Byte offsets of one occurrence within a matched line, end exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Submatch {
+    pub start: usize,
+    pub end: usize,
+}
+
+// What a run found, which main turns into grep's exit code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub matched: bool,
+    pub had_errors: bool,
+    // Whoever reads the output stopped early (grep foo log | head -1), which ended the run
+    // without being an error
+    pub output_closed: bool,
+    pub stats: Stats,
+}
+
+// Totals for --stats. Under -v every selected line counts as one match, and modes that stop
+// reading a file early (-q, -l, -L, binary files) only count what they read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub files_searched: usize,
+    pub selected_lines: usize,
+    pub matches: usize,
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    // What searching a single file found
+    fn file(selected_lines: usize, matches: usize) -> Stats {
+        Stats {
+            files_searched: 1,
+            selected_lines,
+            matches,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    fn add(&mut self, other: Stats) {
+        self.files_searched += other.files_searched;
+        self.selected_lines += other.selected_lines;
+        self.matches += other.matches;
+        self.elapsed += other.elapsed;
     }
 }
 
- /**
+/**
  * print filenames + color output + recursive directory search
  * recursive directory search + print filenames
  * recursive directory search
  * invert match
  * line number
  * multiple files
+ * regex pattern
  * 
 */
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut results = Vec::new();
+pub fn run(config: Config) -> Result<Summary, Box<dyn Error>> {
+    // Buffer stdout so many small lines don't each cost a write, except that on a terminal
+    // each line goes out as soon as it's complete, so someone watching sees it right away.
+    // So does everything read from standard input, which may be a pipe that never ends, as
+    // in tail -f log | grep X | less
+    let stdout = io::stdout();
+    let mut out: Box<dyn Write> = if stdout.is_terminal() || config.file_paths.is_empty() {
+        Box::new(LineWriter::new(stdout.lock()))
+    } else {
+        Box::new(BufWriter::new(stdout.lock()))
+    };
+    let mut summary = run_with_writer(&config, &mut out)?;
+    match out.flush() {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => summary.output_closed = true,
+        flushed => flushed?,
+    }
+    // After the flush, so the totals come after the results they describe
+    if config.stats {
+        let stats = summary.stats;
+        eprintln!("{} files searched", stats.files_searched);
+        eprintln!("{} lines selected", stats.selected_lines);
+        eprintln!("{} matches", stats.matches);
+        eprintln!("{:.3} seconds", stats.elapsed.as_secs_f64());
+    }
+    Ok(summary)
+}
+// Like run, but write the results to `out` instead of stdout. This doesn't go through
+// search_matches: context lines, -q stopping at the first match, binary file notices and
+// printing a file while it is still being read don't fit a finished list of Matches. Both
+// walk files with for_each_file, select lines with is_match (or select_multiline under
+// --multiline) and stop at --max-matches, so they agree on what matches
+pub fn run_with_writer(config: &Config, out: &mut dyn Write) -> Result<Summary, Box<dyn Error>> {
+    if config.list_files {
+        return Ok(list_files(config, out)?);
+    }
+    // No single file can select more lines than --max-matches allows for the whole run
+    let capped;
+    let config = match config.max_total_matches {
+        Some(total) => {
+            let max_count = Some(config.max_count.map_or(total, |max| max.min(total)));
+            capped = Config { max_count, ..config.clone() };
+            &capped
+        }
+        None => config,
+    };
+    // Compile the pattern once up front rather than for every line
+    let matcher = Matcher::new(config)?;
+    let separate_groups = uses_group_separator(config);
+    let group_separator = config.group_separator.as_deref().unwrap_or_default();
+    // `colored` makes its own guess about the terminal; the decision is already in the config
+    colored::control::set_override(config.colored_output);
+    // Whether anything has been printed yet, so the next file's first group gets a separator
+    let mut printed_group = false;
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    let mut write_error = None;
 
-    for file_path in &config.file_paths {
-        if config.recursive_search {
-            results.extend(search_recursive(&config.query, file_path.to_str().unwrap()));
-        } else {
-            let contents = fs::read_to_string(file_path)?;
-            let file_results = if config.case_insensitive {
-                search_case_insensitive(&config.query, &contents)
-            } else if config.invert_match {
-                invert_search(&config.query, &contents)
-            } else if config.line_number {
-                print_linenumbers(&config, &contents)
-            } else {
-                search(&config.query, &contents)
-            };
+    // On the thread pool each file is printed into a buffer of its own, to be written out in
+    // order once the files before it are done. --in-place never gets here; see search_tree
+    let work = |file_path: &Path,
+                reader: io::Result<&mut dyn BufRead>|
+     -> io::Result<Option<(Vec<u8>, Stats)>> {
+        search_to_buffer(config, &matcher, file_path, reader?)
+    };
 
-            for line in file_results {
-                results.push(format!("{}: {}", file_path.display(), line));
+    for_each_file(config, work, |file_path, visit| {
+        // The next file's output is set apart from what came before, once it prints anything
+        let separator = match (printed_group, config.heading, separate_groups) {
+            (false, _, _) => None,
+            (true, true, _) => Some("\n".to_string()),
+            (true, false, true) => {
+                Some(format!("{}\n", paint(group_separator, config.separator_color, config)))
             }
+            (true, false, false) => None,
+        };
+        let mut sink = Separated { out: &mut *out, separator, wrote: false, error: None };
+        let selected = summary.stats.selected_lines;
+        let fits = |stats: &Stats| {
+            config.max_total_matches.is_none_or(|total| selected + stats.selected_lines <= total)
+        };
+        let result = match visit {
+            // Files are searched ahead of being printed. One whose output didn't fit its
+            // buffer, or that reaches --max-matches, is searched again now and printed as
+            // it's read, stopping where the run has to
+            Visit::Done(result) => match result {
+                Ok(Some((output, stats))) if fits(&stats) => sink.write_all(&output).map(|_| stats),
+                Ok(_) => open_and(config, file_path, |path, reader| {
+                    print_file(config, &matcher, path, reader?, selected, &mut sink)
+                }),
+                Err(e) => Err(e),
+            },
+            // Anything else is printed as it's read, so a pipe such as tail -f shows each
+            // match as soon as it arrives and memory doesn't grow with the output
+            Visit::Open(reader) => reader.and_then(|reader| {
+                // The edit reads the file's bytes itself, to write them back in the same
+                // encoding; the reader only says that the file could be opened
+                if let (Some(suffix), Some(replacement)) = (&config.in_place, &config.replacement) {
+                    let edited = edit_in_place(config, &matcher, file_path, replacement, suffix)?;
+                    return Ok(Stats::file(edited as usize, edited as usize));
+                }
+                print_file(config, &matcher, file_path, reader, selected, &mut sink)
+            }),
+        };
+        printed_group |= sink.wrote;
+        // Nothing more can be written (e.g. a closed pipe), so stop searching. What the
+        // reader got before it went away was selected lines, or context around them, except
+        // that --count and -L print something for files without a match too
+        if let Some(e) = sink.error {
+            let per_file = config.count_only || config.count_matches;
+            summary.matched |= !(per_file || config.files_without_matches);
+            write_error = Some(e);
+            return ControlFlow::Break(());
+        }
+        match result {
+            Ok(stats) => {
+                summary.matched |= stats.selected_lines > 0;
+                summary.stats.add(stats);
+            }
+            // Text we can't decode is skipped rather than treated as a failure. Whatever was
+            // printed before the bad bytes turned up stays printed
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                if !config.quiet && !config.no_messages {
+                    eprintln!("grep: {}: skipped, not valid UTF-8 text", file_path.display());
+                }
+            }
+            // Report the unreadable file and carry on with the rest, like grep
+            // -s only silences the message; the exit code still reports the error
+            Err(e) => {
+                summary.had_errors = true;
+                // --json reports the failure in-band so tools see it alongside the matches
+                if config.json && !config.no_messages {
+                    let record = serde_json::json!({
+                        "type": "error",
+                        "path": displayed_path(file_path, config),
+                        "message": describe_io_error(&e),
+                    });
+                    if let Err(e) = writeln!(out, "{}", record) {
+                        write_error = Some(e);
+                        return ControlFlow::Break(());
+                    }
+                } else if !config.no_messages {
+                    eprintln!("grep: {}: {}", file_path.display(), describe_io_error(&e));
+                }
+            }
+        }
+        // In quiet mode the answer is known as soon as anything matches
+        let reached_cap =
+            config.max_total_matches.is_some_and(|total| summary.stats.selected_lines >= total);
+        if (config.quiet && summary.matched) || reached_cap {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    });
+    summary.stats.elapsed = started.elapsed();
+    // The per-file counts already went by, so the total is just what the stats summed up
+    if config.total && !config.quiet && write_error.is_none() {
+        let stats = &summary.stats;
+        let total = if config.count_matches { stats.matches } else { stats.selected_lines };
+        if let Err(e) = writeln!(out, "total: {}", total) {
+            write_error = Some(e);
         }
     }
 
-    // Print the results using the print_result function
-    for result in results {
-        let (file_path, line) = result.split_once(": ").unwrap();
-        print_result(&PathBuf::from(file_path), line, &config);
-    }
-
-    Ok(())
-}
-// Perform Basic Search: refer to the io project in the Rust book
-pub fn search(query: &str, contents: &str) -> Vec<String> {
-    let mut results = Vec::new();
-    for line in contents.lines() {
-        if line.contains(query) {
-            results.push(line.to_string()); // Convert &str to String
+    match write_error {
+        Some(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            Ok(Summary { output_closed: true, ..summary })
         }
+        Some(e) => Err(e.into()),
+        None => Ok(summary),
     }
-    results
 }
-// Perform case insensitive search: refer to the io project in the Rust book
-pub fn search_case_insensitive(query: &str, contents: &str) -> Vec<String> {
-    let query = query.to_lowercase();
-    let mut results = Vec::new();
+// Writes one file's output through to `out`, but first whatever sets it apart from the
+// previous file's (a blank line under --heading, or the group separator), and only once the
+// file prints something. A failed write is kept, to tell it apart from failing to read
+struct Separated<'a> {
+    out: &'a mut dyn Write,
+    separator: Option<String>,
+    wrote: bool,
+    error: Option<io::Error>,
+}
 
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query) {
-            results.push(line.to_string()); // Convert &str to String
+impl Write for Separated<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.wrote = true;
+        let written = match self.separator.take() {
+            Some(separator) => self.out.write_all(separator.as_bytes()),
+            None => Ok(()),
+        };
+        match written.and_then(|_| self.out.write(buf)) {
+            Ok(written) => Ok(written),
+            Err(e) => {
+                let kind = e.kind();
+                self.error = Some(e);
+                Err(io::Error::from(kind))
+            }
         }
     }
-    results
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
 }
-// Perform unmatching search
-pub fn invert_search(query: &str,dir: & str) -> Vec<String>{
-    let query = query;
-    let mut results = Vec::new();
-    for line in dir.lines() {
-        if !line.contains(&query) {
-            results.push(line.to_string());
+// Print one file as it's read, stopping where --max-matches ends the run, given the lines
+// already `selected` in the files before it
+fn print_file(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    reader: &mut dyn BufRead,
+    selected: usize,
+    out: &mut dyn Write,
+) -> io::Result<Stats> {
+    let remaining = config.max_total_matches.map(|total| total.saturating_sub(selected));
+    match remaining {
+        Some(remaining) if config.max_count.is_none_or(|max| remaining < max) => {
+            let partial = Config { max_count: Some(remaining), ..config.clone() };
+            search_and_print(&partial, matcher, file_path, reader, out)
         }
+        _ => search_and_print(config, matcher, file_path, reader, out),
     }
+}
+// How much of one file's output the thread pool holds while the files before it are printed
+const BUFFER_LIMIT: usize = 1 << 20;
 
-    results
+// Search one file into a buffer of its own, for run_with_writer to print in order. The
+// search gives up once the output passes BUFFER_LIMIT, and None says the file has to be
+// searched again when its turn comes, so memory doesn't grow with the size of a file
+fn search_to_buffer(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    reader: &mut dyn BufRead,
+) -> io::Result<Option<(Vec<u8>, Stats)>> {
+    let mut output = Capped { buffer: Vec::new(), full: false };
+    match search_and_print(config, matcher, file_path, reader, &mut output) {
+        Ok(stats) => Ok(Some((output.buffer, stats))),
+        Err(_) if output.full => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+// A buffer that refuses writes past BUFFER_LIMIT, remembering that it did
+struct Capped {
+    buffer: Vec<u8>,
+    full: bool,
 }
-// Perform recursive searching for all satisfied files
-pub fn search_recursive(query: &str, folder: &str) -> Vec<String> {
-    let mut all_results = Vec::new();
 
-    // WalkDir performs a depth-first search by default
-    for entry in WalkDir::new(folder).into_iter().filter_map(|e| e.ok()) {
-        if entry.file_type().is_file() {
-            let file_path = entry.path();
-            if let Ok(contents) = fs::read_to_string(file_path) {
-                let results = search(query, &contents);
-                for line in results {
-                    all_results.push(format!("{}: {}", file_path.display(), line));
-                }
-            }
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > BUFFER_LIMIT {
+            self.full = true;
+            return Err(io::Error::other("output buffer is full"));
         }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
     }
 
-    // Reverse the results to get deepest matches first
-    all_results.reverse();
-    all_results
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
-//Print result with line number
-pub fn print_linenumbers(config: &Config,dir: & str)->Vec<String> {
-    let mut results = Vec::new();
-    // Iterate over the lines with their line numbers (starting from 1)
-    for (line_number, line) in dir.lines().enumerate() {
-        if !line.contains(&config.query) && config.invert_match {
-            // Format the line as "line_number: line"
-            results.push(format!("{}: {}", line_number + 1, line));
-        }else if line.contains(&config.query) {
-            results.push(format!("{}: {}", line_number + 1, line));
+// Print every file a search would read, in the same order, without opening any of them.
+// "Matched" means at least one file was listed
+fn list_files(config: &Config, out: &mut dyn Write) -> io::Result<Summary> {
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    let terminator = if config.null_separator { '\0' } else { '\n' };
+    'paths: for file_path in &config.file_paths {
+        let files = if config.recursive_search && file_path.is_dir() {
+            search_order(config, file_path)
+        } else if file_path.exists() {
+            vec![Ok(file_path.clone())]
+        } else {
+            vec![Err((file_path.clone(), io::Error::from(io::ErrorKind::NotFound)))]
+        };
+        for walked in files {
+            match walked {
+                Ok(path) => {
+                    match write!(out, "{}{}", displayed_path(&path, config), terminator) {
+                        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                            summary.output_closed = true;
+                            break 'paths;
+                        }
+                        written => written?,
+                    }
+                    summary.matched = true;
+                }
+                Err((path, e)) => {
+                    summary.had_errors = true;
+                    if !config.no_messages {
+                        eprintln!("grep: {}: {}", path.display(), describe_io_error(&e));
+                    }
+                }
+            }
         }
     }
-    results
-}
-fn highlight_query(line: &str, query: &str) -> String {
-    let colored_query = query.red().bold(); // Color and style the query
-    line.replace(query, &colored_query.to_string()) // Replace query with colored version
+    summary.stats.elapsed = started.elapsed();
+    Ok(summary)
 }
-// Define the print_result function to handle printing logic
-fn print_result(file_path: &PathBuf, line: &str,  config: &Config) {
-    if config.colored_output && config.print_filenames {
-        println!("{}: {}", file_path.display(), highlight_query(line,  &config.query)); // Use `file_path`
-    } else if config.colored_output {
-        println!("{}", highlight_query(line, &config.query));
-    } else if config.print_filenames {
-        println!("{}: {}", file_path.display(), line); // Use `file_path`
-    } else {
-        println!("{}", line);
+// Search every configured file and return the selected lines instead of printing them
+pub fn search_matches(config: &Config) -> Result<Vec<Match>, Box<dyn Error>> {
+    let matcher = Matcher::new(config)?;
+    let mut matches = Vec::new();
+    let mut failure = None;
+
+    let work = |file_path: &Path, reader: io::Result<&mut dyn BufRead>| {
+        search_file(config, &matcher, file_path, reader?)
+    };
+    for_each_file(config, work, |file_path, visit| match visit.finish(file_path, work) {
+        Ok(file_matches) => {
+            matches.extend(file_matches);
+            // --max-matches ends the search where run stops printing
+            match config.max_total_matches {
+                Some(total) if matches.len() >= total => {
+                    matches.truncate(total);
+                    ControlFlow::Break(())
+                }
+                _ => ControlFlow::Continue(()),
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => ControlFlow::Continue(()),
+        Err(e) => {
+            failure = Some(io::Error::new(e.kind(), format!("{}: {}", file_path.display(), e)));
+            ControlFlow::Break(())
+        }
+    });
+
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(matches),
     }
 }
+// Like search_matches, but lazy: each file is opened and read only as the iterator gets to
+// it, so callers can handle matches one at a time without holding them all. A file that
+// can't be read (or isn't valid UTF-8) yields one Err, and the search moves on to the next
+pub fn search_iter<'a>(config: &'a Config) -> impl Iterator<Item = io::Result<Match>> + 'a {
+    let mut setup_error = None;
+    let matcher = match Matcher::new(config) {
+        Ok(matcher) => Some(matcher),
+        Err(e) => {
+            setup_error = Some(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
+            None
+        }
+    };
+    let describe = |path: &Path, e: io::Error| {
+        io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+    };
+    // Directories are walked under -r (in the same order as a normal search) and are an error
+    // otherwise
+    let mut files = config.file_paths.iter().flat_map(move |path| {
+        if path.is_dir() && config.recursive_search {
+            search_order(config, path)
+                .into_iter()
+                .map(|walked| walked.map_err(|(path, e)| describe(&path, e)))
+                .collect()
+        } else if path.is_dir() {
+            let e = io::Error::new(io::ErrorKind::IsADirectory, "Is a directory");
+            vec![Err(describe(path, e))]
+        } else {
+            vec![Ok(path.clone())]
+        }
+    });
+    let mut read_stdin = config.file_paths.is_empty();
+    // The file being read: its path, its numbered lines and how many were selected so far
+    type Lines<'a> = Box<dyn Iterator<Item = (usize, io::Result<String>)> + 'a>;
+    let mut current: Option<(PathBuf, Lines<'a>, usize)> = None;
+    // A --multiline search reads a whole file at once, leaving its matches here
+    let mut pending = Vec::new().into_iter();
+    // Matches handed out so far, for --max-matches to end the search where run stops printing
+    let mut yielded = 0;
 
+    std::iter::from_fn(move || loop {
+        if let Some(e) = setup_error.take() {
+            return Some(Err(e));
+        }
+        let matcher = matcher.as_ref()?;
+        if config.max_total_matches.is_some_and(|total| yielded >= total) {
+            return None;
+        }
+        if let Some(found) = pending.next() {
+            yielded += 1;
+            return Some(Ok(found));
+        }
+        if let Some((path, lines, selected)) = &mut current {
+            if config.max_count.is_some_and(|max| *selected >= max) {
+                current = None;
+                continue;
+            }
+            match lines.next() {
+                Some((index, Ok(line))) => {
+                    if is_match(config, matcher, &line) {
+                        *selected += 1;
+                        yielded += 1;
+                        return Some(Ok(selected_match(config, matcher, path, index + 1, line)));
+                    }
+                }
+                Some((_, Err(e))) => {
+                    let e = describe(path, e);
+                    current = None;
+                    return Some(Err(e));
+                }
+                None => current = None,
+            }
+            continue;
+        }
+
+        let (path, mut reader) = if read_stdin {
+            read_stdin = false;
+            let stdin: Box<dyn BufRead> = Box::new(io::stdin().lock());
+            (PathBuf::from("(standard input)"), stdin)
+        } else {
+            let path = match files.next()? {
+                Ok(path) => path,
+                Err(e) => return Some(Err(e)),
+            };
+            match open_reader(config, &path) {
+                Ok(reader) => (path, reader),
+                Err(e) => return Some(Err(describe(&path, e))),
+            }
+        };
+        if config.multiline {
+            match search_file(config, matcher, &path, &mut reader) {
+                Ok(found) => pending = found.into_iter(),
+                Err(e) => return Some(Err(describe(&path, e))),
+            }
+            continue;
+        }
+        // Binary files are skipped unless -a asks for them, as in search_matches
+        match looks_binary(config, &mut reader) {
+            Ok(false) => {
+                let lines = read_lines(config, reader, config.text).enumerate();
+                current = Some((path, Box::new(lines), 0));
+            }
+            Ok(true) => {}
+            Err(e) => return Some(Err(describe(&path, e))),
+        }
+    })
+}
+// A file as for_each_file hands it to `consume`: already handled by `work` on the thread
+// pool, or open (or failed to open) for `consume` to read itself as it goes
+enum Visit<'r, R> {
+    Done(R),
+    Open(io::Result<&'r mut dyn BufRead>),
+}
+
+impl<R> Visit<'_, R> {
+    // What `work` makes of the file, running it now if the pool didn't already
+    fn finish(self, path: &Path, work: impl FnOnce(&Path, io::Result<&mut dyn BufRead>) -> R) -> R {
+        match self {
+            Visit::Done(result) => result,
+            Visit::Open(reader) => work(path, reader),
+        }
+    }
+}
+// Open each configured file, walking directories under -r, and hand it to `consume` in order,
+// which can break to stop early. Only a recursive search on the thread pool runs `work` on
+// files ahead of time; everything else, standard input included, reaches `consume` still
+// open. Standard input stands in for the file list when it is empty
+fn for_each_file<R: Send>(
+    config: &Config,
+    work: impl Fn(&Path, io::Result<&mut dyn BufRead>) -> R + Sync,
+    mut consume: impl FnMut(&Path, Visit<'_, R>) -> ControlFlow<()>,
+) {
+    if config.file_paths.is_empty() {
+        let stdin = Path::new("(standard input)");
+        let _ = consume(stdin, Visit::Open(Ok(&mut io::stdin().lock())));
+    }
+
+    for file_path in &config.file_paths {
+        let flow = if config.recursive_search {
+            search_tree(config, file_path, &work, &mut consume)
+        } else if file_path.is_dir() {
+            // Reading a directory fails with an OS-specific error, so name the problem up front
+            let is_dir = io::Error::from(io::ErrorKind::IsADirectory);
+            consume(file_path, Visit::Open(Err(is_dir)))
+        } else {
+            open_and(config, file_path, |path, reader| consume(path, Visit::Open(reader)))
+        };
+        if flow.is_break() {
+            break;
+        }
+    }
+}
+// Like open_and, for a file found by walking a folder: one the walk couldn't read hands
+// `work` the walk's error instead
+fn open_walked<R>(
+    config: &Config,
+    walked: &Walked,
+    work: impl FnOnce(&Path, io::Result<&mut dyn BufRead>) -> R,
+) -> R {
+    match walked {
+        Ok(path) => open_and(config, path, work),
+        Err((path, e)) => work(path, Err(io::Error::new(e.kind(), e.to_string()))),
+    }
+}
+// Open one file and hand a buffered reader over it, or the error opening it, to `work`.
+// With --search-zip a .gz file is read through a decoder, so a corrupt stream shows up as a
+// read error for that file
+fn open_and<R>(
+    config: &Config,
+    file_path: &Path,
+    work: impl FnOnce(&Path, io::Result<&mut dyn BufRead>) -> R,
+) -> R {
+    match open_reader(config, file_path) {
+        Ok(mut reader) => work(file_path, Ok(&mut reader)),
+        Err(e) => work(file_path, Err(e)),
+    }
+}
+// A buffered reader over one file, preprocessing, decompressing and decoding it as needed
+fn open_reader(config: &Config, file_path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader: Box<dyn BufRead> = if let Some(command) = &config.pre {
+        Box::new(Cursor::new(preprocess(command, file_path)?))
+    } else if config.search_zip && file_path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(fs::File::open(file_path)?)))
+    } else {
+        Box::new(BufReader::new(fs::File::open(file_path)?))
+    };
+    match decode_utf16(&mut reader)? {
+        Some(decoded) => Ok(Box::new(io::Cursor::new(decoded))),
+        None => Ok(reader),
+    }
+}
+// Run the --pre command on one file and return what it printed, failing if it can't be run
+// or exits unsuccessfully. The path is passed as an argument rather than through a shell,
+// so odd file names can't inject commands, but the command itself runs with the user's
+// permissions on every file the search reaches
+fn preprocess(command: &str, file_path: &Path) -> io::Result<Vec<u8>> {
+    let output = Command::new(command)
+        .arg(file_path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| io::Error::other(format!("could not run --pre command {}: {}", command, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = match stderr.lines().next() {
+            Some(line) => line.to_string(),
+            None => output.status.to_string(),
+        };
+        return Err(io::Error::other(format!("--pre command {} failed: {}", command, reason)));
+    }
+    Ok(output.stdout)
+}
+// Text starting with a UTF-16 byte order mark is decoded to UTF-8 up front, dropping the mark
+// so it doesn't show up in the first line. Anything else is left to be read as it is
+fn decode_utf16(reader: &mut dyn BufRead) -> io::Result<Option<Vec<u8>>> {
+    let Some(encoding) = utf16_encoding(reader.fill_buf()?) else {
+        return Ok(None);
+    };
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let (text, _) = encoding.decode_with_bom_removal(&bytes);
+    Ok(Some(text.into_owned().into_bytes()))
+}
+// Which UTF-16 the byte order mark at the start of `bytes` announces, if there is one
+fn utf16_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    match bytes {
+        [0xFF, 0xFE, ..] => Some(UTF_16LE),
+        [0xFE, 0xFF, ..] => Some(UTF_16BE),
+        _ => None,
+    }
+}
+// Encode `text` as UTF-16 in the byte order of `encoding`, starting with a byte order mark
+fn encode_utf16(text: &str, encoding: &'static Encoding) -> Vec<u8> {
+    std::iter::once(0xFEFF)
+        .chain(text.encode_utf16())
+        .flat_map(|unit| if encoding == UTF_16BE { unit.to_be_bytes() } else { unit.to_le_bytes() })
+        .collect()
+}
+// Turn an io::Error into the short wording grep uses, without the "(os error N)" suffix
+fn describe_io_error(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::NotFound => "No such file or directory".to_string(),
+        io::ErrorKind::PermissionDenied => "Permission denied".to_string(),
+        io::ErrorKind::IsADirectory => "Is a directory".to_string(),
+        _ => e.to_string(),
+    }
+}
+// The queries prepared once per run, so nothing is compiled or lowercased again per line
+struct Matcher {
+    // For -E, and for -i, whose case folding is left to the regex engine
+    regex: Option<Regex>,
+    // The queries lowercased for --fuzzy -i
+    lowercase_queries: Vec<String>,
+    // Substring searchers for the case-sensitive literal path. benches/literal.rs has them on a
+    // par with str::contains for short lines and ahead once lines run to a few kilobytes
+    finders: Vec<Finder<'static>>,
+    // With --all-match and several queries, a single-query config and matcher for each
+    all_of: Vec<(Config, Matcher)>,
+    // The --and-not pattern, matched anywhere in the line as -i, -E and -F say
+    excluded: Option<Box<(Config, Matcher)>>,
+    // For --binary, when the finders alone can't do it (-E, -i or -w)
+    binary_regex: Option<regex::bytes::Regex>,
+}
+
+impl Matcher {
+    fn new(config: &Config) -> Result<Matcher, Box<dyn Error>> {
+        let lowercase_queries = if config.case_insensitive && config.fuzzy.is_some() {
+            config.queries.iter().map(|query| query.to_lowercase()).collect()
+        } else {
+            Vec::new()
+        };
+        let finders = config
+            .queries
+            .iter()
+            .map(|query| Finder::new(query.as_bytes()).into_owned())
+            .collect();
+        let mut all_of = Vec::new();
+        if config.all_match && config.queries.len() > 1 {
+            for query in &config.queries {
+                let single = Config {
+                    queries: vec![query.clone()],
+                    all_match: false,
+                    ..config.clone()
+                };
+                let matcher = Matcher::new(&single)?;
+                all_of.push((single, matcher));
+            }
+        }
+        let excluded = match &config.exclude_pattern {
+            Some(pattern) => {
+                let single = Config {
+                    queries: vec![pattern.clone()],
+                    all_match: false,
+                    exclude_pattern: None,
+                    word_match: false,
+                    line_match: false,
+                    ..config.clone()
+                };
+                let matcher = Matcher::new(&single)?;
+                Some(Box::new((single, matcher)))
+            }
+            None => None,
+        };
+        Ok(Matcher {
+            regex: build_regex(config)?,
+            lowercase_queries,
+            finders,
+            all_of,
+            excluded,
+            binary_regex: build_binary_regex(config)?,
+        })
+    }
+}
+// Build the regex for -E, letting the regex engine handle case-insensitivity, -w and -x.
+// -F wins over -E, leaving the literal search, except under -i: literal queries are then
+// escaped into a regex too, so -i always means the engine's Unicode simple case folding.
+// That folds each character on its own (K also matches the Kelvin sign, and σ, ς and Σ all
+// match each other) but never changes a length, so ß doesn't match "ss"
+fn build_regex(config: &Config) -> Result<Option<Regex>, Box<dyn Error>> {
+    let use_regex = config.use_regex && !config.fixed_strings;
+    if !use_regex && !config.case_insensitive {
+        return Ok(None);
+    }
+    // Several -e patterns become one alternation; with no patterns at all, use an
+    // assertion that can never hold so nothing matches
+    let alternatives: Vec<String> = config
+        .queries
+        .iter()
+        .map(|query| if use_regex { format!("(?:{})", query) } else { regex::escape(query) })
+        .collect();
+    let alternatives = if alternatives.is_empty() {
+        r"\b\B".to_string()
+    } else {
+        alternatives.join("|")
+    };
+    // -x is always about single lines, even when the whole file is searched at once
+    let pattern = if config.line_match {
+        format!("(?m:^(?:{})$)", alternatives)
+    } else if config.word_match {
+        format!(r"\b(?:{})\b", alternatives)
+    } else {
+        alternatives
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(config.case_insensitive)
+        .multi_line(config.multiline_regex)
+        .dot_matches_new_line(config.dotall)
+        .build()
+        .map(Some)
+        .map_err(|e| {
+            format!("Invalid regex pattern '{}': {}", config.queries.join("', '"), e).into()
+        })
+}
+// The byte-level regex --binary uses instead of the finders for -E, -i or -w. There are no
+// lines in raw bytes, so -x isn't applied
+fn build_binary_regex(config: &Config) -> Result<Option<regex::bytes::Regex>, Box<dyn Error>> {
+    let use_regex = config.use_regex && !config.fixed_strings;
+    if !config.binary || !(use_regex || config.case_insensitive || config.word_match) {
+        return Ok(None);
+    }
+    let alternatives: Vec<String> = config
+        .queries
+        .iter()
+        .map(|query| if use_regex { format!("(?:{})", query) } else { regex::escape(query) })
+        .collect();
+    let alternatives = alternatives.join("|");
+    let pattern = if config.word_match {
+        format!(r"\b(?:{})\b", alternatives)
+    } else {
+        alternatives
+    };
+    Ok(Some(
+        regex::bytes::RegexBuilder::new(&pattern)
+            .case_insensitive(config.case_insensitive)
+            .build()?,
+    ))
+}
+// Check a single line against the queries, honoring -i, -v, -E, --all-match and --and-not
+fn is_match(config: &Config, matcher: &Matcher, line: &str) -> bool {
+    let matched = if matcher.all_of.is_empty() {
+        matches_any(config, matcher, line)
+    } else {
+        matcher.all_of.iter().all(|(single, matcher)| matches_any(single, matcher, line))
+    };
+    let excluded = matcher
+        .excluded
+        .as_ref()
+        .is_some_and(|excluded| matches_any(&excluded.0, &excluded.1, line));
+    (matched && !excluded) != config.invert_match
+}
+// Whether any of the queries occurs in the line, before -v is applied
+fn matches_any(config: &Config, matcher: &Matcher, line: &str) -> bool {
+    if let Some(max_distance) = config.fuzzy {
+        return is_fuzzy_match(config, matcher, line, max_distance);
+    }
+    match &matcher.regex {
+        Some(re) => re.is_match(line),
+        None if config.line_match => is_whole_line_match(config, line),
+        None if config.word_match => !match_ranges(config, matcher, line).is_empty(),
+        None => matcher.finders.iter().any(|finder| finder.find(line.as_bytes()).is_some()),
+    }
+}
+// Whether some run of characters in the line is within `max_distance` edits of a query.
+// Every window from max_distance shorter to max_distance longer than the query is tried at
+// every position, so this costs roughly line length x query length x (2 x max_distance + 1)
+// per query, against one memchr-accelerated scan for a literal search
+fn is_fuzzy_match(config: &Config, matcher: &Matcher, line: &str, max_distance: usize) -> bool {
+    let (line, queries) = if config.case_insensitive {
+        (line.to_lowercase(), &matcher.lowercase_queries)
+    } else {
+        (line.to_string(), &config.queries)
+    };
+    // Windows are measured in characters, so slice the line at character boundaries
+    let bounds: Vec<usize> = line.char_indices().map(|(at, _)| at).chain([line.len()]).collect();
+    let chars = bounds.len() - 1;
+    queries.iter().any(|query| {
+        let query_chars = query.chars().count();
+        let shortest = query_chars.saturating_sub(max_distance);
+        let longest = query_chars + max_distance;
+        (0..=chars).any(|start| {
+            (shortest..=longest).any(|length| {
+                let window = &line[bounds[start]..bounds[chars.min(start + length)]];
+                strsim::levenshtein(window, query) <= max_distance
+            })
+        })
+    })
+}
+// Byte ranges of each non-overlapping occurrence of any query within `line`. Empty matches
+// (from an empty query, or a pattern like a*) select the line but are left out here, since
+// there is nothing to print or color, and an empty query would otherwise hit every position
+fn match_ranges(config: &Config, matcher: &Matcher, line: &str) -> Vec<(usize, usize)> {
+    // A fuzzy match has no exact extent, so it isn't located within the line
+    if config.fuzzy.is_some() {
+        return Vec::new();
+    }
+    match &matcher.regex {
+        // -w and -x are already part of the compiled pattern
+        Some(re) => re
+            .find_iter(line)
+            .filter(|m| !m.is_empty())
+            .map(|m| (m.start(), m.end()))
+            .collect(),
+        None if config.line_match => {
+            if !line.is_empty() && is_whole_line_match(config, line) {
+                vec![(0, line.len())]
+            } else {
+                Vec::new()
+            }
+        }
+        None => {
+            let mut ranges = Vec::new();
+            for query in config.queries.iter().filter(|query| !query.is_empty()) {
+                let mut from = 0;
+                while let Some(found) = line[from..].find(query.as_str()) {
+                    let (start, end) = (from + found, from + found + query.len());
+                    if !config.word_match || is_word_bounded(line, start, end) {
+                        ranges.push((start, end));
+                        from = end;
+                    } else {
+                        // A hit -w rejects may overlap one it accepts, as "ab ab" does in
+                        // "xab ab ab", so look again from the next character
+                        from = start + line[start..].chars().next().map_or(1, char::len_utf8);
+                    }
+                }
+            }
+            leftmost_non_overlapping(ranges)
+        }
+    }
+}
+// Different queries can hit overlapping text. Keep the leftmost (then longest) hit and drop
+// whatever overlaps it, the same choice a regex alternation would make
+fn leftmost_non_overlapping(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    let mut kept: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        if kept.last().is_none_or(|&(_, end)| range.0 >= end) {
+            kept.push(range);
+        }
+    }
+    kept
+}
+// For -x, the line (already stripped of its line ending) must equal one of the queries
+fn is_whole_line_match(config: &Config, line: &str) -> bool {
+    config.queries.iter().any(|query| line == query)
+}
+// For -w, the characters on either side of a match must not be word characters
+fn is_word_bounded(line: &str, start: usize, end: usize) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let before = line[..start].chars().next_back();
+    let after = line[end..].chars().next();
+    !before.is_some_and(is_word) && !after.is_some_and(is_word)
+}
+// Like grep, call a file binary if a NUL byte shows up in its first buffer full. This only
+// peeks, so nothing is consumed from the reader. -a says nothing is binary, and under -z a
+// NUL just ends a line
+fn looks_binary(config: &Config, reader: &mut dyn BufRead) -> io::Result<bool> {
+    if config.text || config.null_data {
+        return Ok(false);
+    }
+    Ok(memchr::memchr(0, reader.fill_buf()?).is_some())
+}
+// The lines of a file without their "\n" or "\r\n" endings (or NUL under -z). A "\r" is
+// dropped even on a last line with no "\n" after it, since it would break -x and leave a
+// stray carriage return in the output. Text must be valid UTF-8 (an InvalidData error
+// otherwise), while `lossy` replaces anything invalid instead
+fn read_lines<'a>(
+    config: &Config,
+    reader: impl BufRead + 'a,
+    lossy: bool,
+) -> impl Iterator<Item = io::Result<String>> + 'a {
+    read_lines_with_offsets(config, reader, lossy).map(|line| line.map(|(line, _)| line))
+}
+// Like read_lines, but each line comes with the byte offset in the file where it starts
+fn read_lines_with_offsets<'a>(
+    config: &Config,
+    mut reader: impl BufRead + 'a,
+    lossy: bool,
+) -> impl Iterator<Item = io::Result<(String, usize)>> + 'a {
+    let null_data = config.null_data;
+    let terminator = if null_data { b'\0' } else { b'\n' };
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let mut bytes = Vec::new();
+        let read = match reader.read_until(terminator, &mut bytes) {
+            Ok(0) => return None,
+            Ok(read) => read,
+            Err(e) => return Some(Err(e)),
+        };
+        let start = offset;
+        offset += read;
+        if bytes.last() == Some(&terminator) {
+            bytes.pop();
+        }
+        if !null_data && bytes.last() == Some(&b'\r') {
+            bytes.pop();
+        }
+        let line = if lossy {
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        } else {
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        Some(line.map(|line| (line, start)))
+    })
+}
+// Search the contents of one file and collect every selected line
+fn search_file(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    reader: &mut dyn BufRead,
+) -> io::Result<Vec<Match>> {
+    let mut results = Vec::new();
+    // Binary files are skipped unless -a asks for them
+    if looks_binary(config, reader)? {
+        return Ok(results);
+    }
+    // A --multiline match can span lines, so the whole file is searched at once, as run does.
+    // Each selected line's submatches are the parts of the matches that fall on it
+    if let (true, Some(re)) = (config.multiline, &matcher.regex) {
+        let contents = read_text(reader, config.text)?;
+        let MultilineSearch { lines, selected, found } = select_multiline(config, re, &contents)?;
+        let selected_lines = lines.into_iter().enumerate().filter(|(index, _)| selected[*index]);
+        for (index, (line, start)) in selected_lines {
+            let end = start + line.len();
+            let submatches = found
+                .iter()
+                .filter(|_| !config.invert_match)
+                .filter(|(_, range)| range.start < end && range.end > start)
+                .map(|(_, range)| Submatch {
+                    start: range.start.max(start) - start,
+                    end: range.end.min(end) - start,
+                })
+                .collect();
+            let file = file_path.to_path_buf();
+            results.push(Match { file, line_number: index + 1, line, submatches });
+        }
+        return Ok(results);
+    }
+    for (line_number, line) in read_lines(config, reader, config.text).enumerate() {
+        if config.max_count.is_some_and(|max| results.len() >= max) {
+            break;
+        }
+        let line = line?;
+        if is_match(config, matcher, &line) {
+            results.push(selected_match(config, matcher, file_path, line_number + 1, line));
+        }
+    }
+    Ok(results)
+}
+// Describe a selected line along with where each query occurs in it
+fn selected_match(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    line_number: usize,
+    line: String,
+) -> Match {
+    let submatches = if config.invert_match {
+        Vec::new()
+    } else {
+        match_ranges(config, matcher, &line)
+            .into_iter()
+            .map(|(start, end)| Submatch { start, end })
+            .collect()
+    };
+    Match {
+        file: file_path.to_path_buf(),
+        line_number,
+        line,
+        submatches,
+    }
+}
+// Whether any line is selected, stopping at the first one so the rest is never read
+fn any_selected(
+    config: &Config,
+    matcher: &Matcher,
+    lines: impl Iterator<Item = io::Result<String>>,
+) -> io::Result<bool> {
+    for line in lines {
+        if is_match(config, matcher, &line?) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+// Groups of lines are separated by "--" when context is printed around them, unless
+// --no-group-separator turned that off
+fn uses_group_separator(config: &Config) -> bool {
+    let prints_lines = !(config.quiet
+        || config.files_with_matches
+        || config.files_without_matches
+        || config.count_only
+        || config.count_matches
+        || config.only_matching
+        || config.vimgrep
+        || config.json);
+    let has_context = config.after_context > 0 || config.before_context > 0;
+    prints_lines && has_context && config.group_separator.is_some()
+}
+// Occurrences on a selected line, for --stats and --only-count-matches (and only worked out
+// when one of them asks); an inverted match counts the line once
+fn count_matches(config: &Config, matcher: &Matcher, line: &str) -> usize {
+    if !(config.stats || config.count_matches) {
+        0
+    } else if config.invert_match {
+        1
+    } else {
+        match_ranges(config, matcher, line).len()
+    }
+}
+// Print the selected lines of one file along with any requested context, returning
+// what was found (any selected line makes the run a match)
+fn search_and_print(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    reader: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> io::Result<Stats> {
+    // A binary file is still matched against (its invalid UTF-8 replaced), but its lines
+    // are never printed
+    let binary = looks_binary(config, reader)?;
+    let lossy = binary || config.text;
+    if config.multiline && matcher.regex.is_some() {
+        return search_multiline(config, matcher, file_path, reader, out, binary);
+    }
+    if config.quiet {
+        let matched = any_selected(config, matcher, read_lines(config, reader, lossy))?;
+        return Ok(Stats::file(matched as usize, matched as usize));
+    }
+    if config.files_with_matches || config.files_without_matches {
+        let matched = any_selected(config, matcher, read_lines(config, reader, lossy))?;
+        if (config.files_with_matches && matched) || (config.files_without_matches && !matched) {
+            print_file_name(out, file_path, config)?;
+        }
+        return Ok(Stats::file(matched as usize, matched as usize));
+    }
+    if config.count_only || config.count_matches {
+        // What's counted is the selection, so with -v these are the lines that don't match,
+        // as in GNU grep, and -m caps the same lines the normal output would stop at
+        let mut count = 0;
+        let mut matches = 0;
+        for line in read_lines(config, reader, lossy) {
+            if config.max_count.is_some_and(|max| count >= max) {
+                break;
+            }
+            let line = line?;
+            if is_match(config, matcher, &line) {
+                count += 1;
+                matches += count_matches(config, matcher, &line);
+            }
+        }
+        let printed = if config.count_matches { matches } else { count };
+        print_count(out, file_path, printed, config)?;
+        return Ok(Stats::file(count, matches));
+    }
+    // --binary looks for the queries in the raw bytes; -v has nothing to point at there
+    if binary && config.binary && !config.invert_match {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut offsets = binary_offsets(matcher, &bytes);
+        offsets.truncate(config.max_count.unwrap_or(usize::MAX));
+        let path = displayed_path(file_path, config);
+        for offset in &offsets {
+            writeln!(out, "Binary file {} matches at offset {}", path, offset)?;
+        }
+        return Ok(Stats::file(!offsets.is_empty() as usize, offsets.len()));
+    }
+    if binary {
+        let matched = any_selected(config, matcher, read_lines(config, reader, lossy))?;
+        if matched {
+            writeln!(out, "Binary file {} matches", displayed_path(file_path, config))?;
+        }
+        return Ok(Stats::file(matched as usize, matched as usize));
+    }
+
+    // -o prints bare matches, so there is no surrounding line to show context from, and
+    // --vimgrep and --json rows must all be matches
+    let structured = config.vimgrep || config.json;
+    let (after_context, before_context) = if config.only_matching || structured {
+        (0, 0)
+    } else {
+        (config.after_context, config.before_context)
+    };
+    // Index of the last line printed from this file, used to detect gaps between groups
+    let mut last_printed: Option<usize> = None;
+    // Number of trailing context lines still owed to the most recent match
+    let mut after_remaining = 0;
+    // Rolling window of recent unprinted lines that may become leading context
+    let mut before: VecDeque<(usize, usize, String)> = VecDeque::with_capacity(before_context);
+    // Selected lines printed so far from this file, checked against -m
+    let mut selected_count = 0;
+    let mut matches = 0;
+
+    // Fitting line numbers to the file needs its line count up front, so only then is the
+    // whole file buffered
+    let mut buffered;
+    let (reader, number_width) = match config.line_number_width {
+        Some(0) if config.line_number || config.heading => {
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents)?;
+            let width = count_lines(config, &contents).to_string().len();
+            buffered = Cursor::new(contents);
+            (&mut buffered as &mut dyn BufRead, width)
+        }
+        width => (reader, width.unwrap_or(0)),
+    };
+
+    // Lines are read one at a time, so memory use doesn't grow with the file
+    for (index, line) in read_lines_with_offsets(config, reader, lossy).enumerate() {
+        let (line, offset) = line?;
+        let location = Location { line_number: index + 1, byte_offset: offset, number_width };
+        let mut selected = is_match(config, matcher, &line);
+        if config.max_count.is_some_and(|max| selected_count >= max) {
+            // Past the limit we only finish the trailing context of the last match
+            if after_remaining == 0 {
+                break;
+            }
+            selected = false;
+        }
+        if selected {
+            selected_count += 1;
+            matches += count_matches(config, matcher, &line);
+        }
+        if !selected && after_remaining == 0 {
+            if before_context > 0 {
+                if before.len() == before_context {
+                    before.pop_front();
+                }
+                before.push_back((index, offset, line));
+            }
+            continue;
+        }
+
+        if config.json {
+            let path = displayed_path(file_path, config);
+            let found = selected_match(config, matcher, Path::new(&path), index + 1, line);
+            serde_json::to_writer(&mut *out, &found)?;
+            writeln!(out)?;
+            continue;
+        }
+        if config.vimgrep {
+            print_vimgrep(out, file_path, index + 1, &line, matcher, config)?;
+            continue;
+        }
+        // Under --heading the path goes above the file's first printed line (-o -v prints none)
+        let prints_nothing = config.only_matching && config.invert_match;
+        if config.heading && last_printed.is_none() && !prints_nothing {
+            let path = displayed_path(file_path, config);
+            writeln!(out, "{}", paint(path, config.path_color, config))?;
+        }
+        // Separate non-adjacent groups with "--" (or --group-separator) like GNU grep
+        let first = before.front().map_or(index, |(before_index, _, _)| *before_index);
+        let adjacent = last_printed.is_some_and(|last| last + 1 == first);
+        if let (true, Some(separator)) = (uses_group_separator(config), &config.group_separator) {
+            if last_printed.is_some() && !adjacent {
+                writeln!(out, "{}", paint(separator, config.separator_color, config))?;
+            }
+        }
+
+        for (before_index, before_offset, before_line) in before.drain(..) {
+            let before_location = Location {
+                line_number: before_index + 1,
+                byte_offset: before_offset,
+                number_width,
+            };
+            print_result(out, file_path, before_location, &before_line, '-', matcher, config)?;
+        }
+        if selected && config.only_matching {
+            // Each occurrence goes on its own line, or with --o-separator all on the one line
+            // (located by the first); -v selects lines without any, so prints nothing
+            let ranges = if config.invert_match {
+                Vec::new()
+            } else {
+                match_ranges(config, matcher, &line)
+            };
+            match (&config.o_separator, ranges.first()) {
+                (Some(joiner), Some(&(first, _))) => {
+                    let matched: Vec<&str> =
+                        ranges.iter().map(|&(start, end)| &line[start..end]).collect();
+                    let match_location = Location { byte_offset: offset + first, ..location };
+                    let joined = matched.join(joiner);
+                    print_result(out, file_path, match_location, &joined, ':', matcher, config)?;
+                }
+                (Some(_), None) => {}
+                (None, _) => {
+                    for (start, end) in ranges {
+                        let matched = &line[start..end];
+                        let location = Location { byte_offset: offset + start, ..location };
+                        print_result(out, file_path, location, matched, ':', matcher, config)?;
+                    }
+                }
+            }
+        } else if selected {
+            print_result(out, file_path, location, &line, ':', matcher, config)?;
+            after_remaining = after_context;
+        } else {
+            print_result(out, file_path, location, &line, '-', matcher, config)?;
+            after_remaining -= 1;
+        }
+        last_printed = Some(index);
+    }
+
+    Ok(Stats::file(selected_count, matches))
+}
+// Where each non-overlapping match starts in a binary file's bytes, in order
+fn binary_offsets(matcher: &Matcher, bytes: &[u8]) -> Vec<usize> {
+    if let Some(re) = &matcher.binary_regex {
+        return re.find_iter(bytes).filter(|m| !m.is_empty()).map(|m| m.start()).collect();
+    }
+    let ranges = matcher
+        .finders
+        .iter()
+        .filter(|finder| !finder.needle().is_empty())
+        .flat_map(|finder| {
+            let length = finder.needle().len();
+            finder.find_iter(bytes).map(move |start| (start, start + length))
+        })
+        .collect();
+    leftmost_non_overlapping(ranges).into_iter().map(|(start, _)| start).collect()
+}
+// search_and_print for --multiline. The regex runs over the whole file, and every line a
+// match touches is selected (-v selects the others). Counts, -m and context then work on
+// those lines, so -A/-B reach out from the first and last line of each match
+fn search_multiline(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    reader: &mut dyn BufRead,
+    out: &mut dyn Write,
+    binary: bool,
+) -> io::Result<Stats> {
+    let Some(re) = &matcher.regex else {
+        return Ok(Stats::default());
+    };
+    let contents = read_text(reader, binary || config.text)?;
+    let MultilineSearch { lines, selected, found } = select_multiline(config, re, &contents)?;
+    let count = selected.iter().filter(|&&is| is).count();
+    let matches = if config.invert_match {
+        count
+    } else {
+        found.iter().filter(|(first, _)| selected[*first]).count()
+    };
+    let stats = Stats::file(count, matches);
+
+    if config.quiet {
+        return Ok(stats);
+    }
+    if config.files_with_matches || config.files_without_matches {
+        let matched = count > 0;
+        if (config.files_with_matches && matched) || (config.files_without_matches && !matched) {
+            print_file_name(out, file_path, config)?;
+        }
+        return Ok(stats);
+    }
+    if config.count_only || config.count_matches {
+        print_count(out, file_path, if config.count_matches { matches } else { count }, config)?;
+        return Ok(stats);
+    }
+    if binary {
+        if count > 0 {
+            writeln!(out, "Binary file {} matches", displayed_path(file_path, config))?;
+        }
+        return Ok(stats);
+    }
+    if count > 0 && config.heading && !(config.only_matching && config.invert_match) {
+        let path = displayed_path(file_path, config);
+        writeln!(out, "{}", paint(path, config.path_color, config))?;
+    }
+    let number_width = match config.line_number_width {
+        Some(0) => lines.len().to_string().len(),
+        width => width.unwrap_or(0),
+    };
+
+    if config.json || config.vimgrep {
+        for (index, (line, _)) in lines.iter().enumerate().filter(|(index, _)| selected[*index]) {
+            if config.json {
+                let path = displayed_path(file_path, config);
+                let found =
+                    selected_match(config, matcher, Path::new(&path), index + 1, line.clone());
+                serde_json::to_writer(&mut *out, &found)?;
+                writeln!(out)?;
+            } else {
+                print_vimgrep(out, file_path, index + 1, line, matcher, config)?;
+            }
+        }
+        return Ok(stats);
+    }
+    // -o prints each whole match, line breaks and all, numbered by the line it starts on
+    if config.only_matching {
+        if !config.invert_match {
+            for (first, range) in found.into_iter().filter(|(first, _)| selected[*first]) {
+                let location =
+                    Location { line_number: first + 1, byte_offset: range.start, number_width };
+                print_result(out, file_path, location, &contents[range], ':', matcher, config)?;
+            }
+        }
+        return Ok(stats);
+    }
+
+    let mut shown = vec![false; lines.len()];
+    for index in (0..lines.len()).filter(|&index| selected[index]) {
+        let last = lines.len().min(index + config.after_context + 1);
+        shown[index.saturating_sub(config.before_context)..last].fill(true);
+    }
+    let mut last_printed: Option<usize> = None;
+    for (index, (line, offset)) in lines.iter().enumerate().filter(|(index, _)| shown[*index]) {
+        if let (true, Some(separator)) = (uses_group_separator(config), &config.group_separator) {
+            if last_printed.is_some_and(|last| last + 1 != index) {
+                writeln!(out, "{}", paint(separator, config.separator_color, config))?;
+            }
+        }
+        let location = Location { line_number: index + 1, byte_offset: *offset, number_width };
+        let separator = if selected[index] { ':' } else { '-' };
+        print_result(out, file_path, location, line, separator, matcher, config)?;
+        last_printed = Some(index);
+    }
+    Ok(stats)
+}
+// The whole of a file as text: valid UTF-8 (an InvalidData error otherwise), while `lossy`
+// replaces anything invalid instead
+fn read_text(reader: &mut dyn BufRead, lossy: bool) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    if lossy {
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    } else {
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+// What a --multiline search finds in a whole file: its lines with the offset each starts
+// at, which of them are selected, and each match with the index of the line it starts on
+struct MultilineSearch {
+    lines: Vec<(String, usize)>,
+    selected: Vec<bool>,
+    found: Vec<(usize, Range<usize>)>,
+}
+// Run the regex over all of `contents`, selecting every line a match touches (-v selects the
+// others) up to -m of them
+fn select_multiline(config: &Config, re: &Regex, contents: &str) -> io::Result<MultilineSearch> {
+    // Split the text the same way a line-by-line search would
+    let lines: Vec<(String, usize)> =
+        read_lines_with_offsets(config, contents.as_bytes(), false).collect::<io::Result<_>>()?;
+    let line_of = |offset: usize| lines.partition_point(|&(_, start)| start <= offset) - 1;
+
+    let mut covered = vec![false; lines.len()];
+    let mut found = Vec::new();
+    for m in re.find_iter(contents).filter(|m| !m.is_empty()) {
+        let first = line_of(m.start());
+        covered[first..=line_of(m.end() - 1)].fill(true);
+        found.push((first, m.range()));
+    }
+    let mut selected: Vec<bool> = covered.iter().map(|&hit| hit != config.invert_match).collect();
+    if let Some(max) = config.max_count {
+        selected.iter_mut().filter(|is| **is).skip(max).for_each(|is| *is = false);
+    }
+    Ok(MultilineSearch { lines, selected, found })
+}
+// The -l/-L line for one file
+fn print_file_name(out: &mut dyn Write, file_path: &Path, config: &Config) -> io::Result<()> {
+    let path = paint(displayed_path(file_path, config), config.path_color, config);
+    if config.null_separator {
+        write!(out, "{}\0", path)
+    } else {
+        writeln!(out, "{}", path)
+    }
+}
+// Number of lines in a buffered file, counting a last line that has no terminator
+fn count_lines(config: &Config, contents: &[u8]) -> usize {
+    let terminator = if config.null_data { b'\0' } else { b'\n' };
+    let terminated = memchr::memchr_iter(terminator, contents).count();
+    match contents.last() {
+        Some(&last) if last != terminator => terminated + 1,
+        _ => terminated,
+    }
+}
+// Perform Basic Search: refer to the io project in the Rust book
+pub fn search(query: &str, contents: &str) -> Vec<String> {
+    let mut results = Vec::new();
+    for line in contents.lines() {
+        if line.contains(query) {
+            results.push(line.to_string()); // Convert &str to String
+        }
+    }
+    results
+}
+// Perform case insensitive search: refer to the io project in the Rust book
+pub fn search_case_insensitive(query: &str, contents: &str) -> Vec<String> {
+    let query = query.to_lowercase();
+    let mut results = Vec::new();
+
+    for line in contents.lines() {
+        if line.to_lowercase().contains(&query) {
+            results.push(line.to_string()); // Convert &str to String
+        }
+    }
+    results
+}
+// Perform unmatching search
+pub fn invert_search(query: &str, dir: &str) -> Vec<String> {
+    let mut results = Vec::new();
+    for line in dir.lines() {
+        if !line.contains(query) {
+            results.push(line.to_string());
+        }
+    }
+
+    results
+}
+// Perform recursive searching for all satisfied files, as "path: line" for every line that
+// contains `query`, in the order of the name-sorted walk. Every file is read, hidden or
+// ignored ones too; the command line goes through run instead
+pub fn search_recursive(query: &str, folder: &Path) -> Vec<String> {
+    let config = ConfigBuilder::new(query)
+        .recursive(true)
+        .respect_ignore(false)
+        .hidden(true)
+        .file(folder)
+        .build();
+    // Unreadable and binary files are skipped, as they always were
+    search_iter(&config)
+        .filter_map(Result::ok)
+        .map(|found| format!("{}: {}", found.file.display(), found.line))
+        .collect()
+}
+//Print result with line number: "N: line" for each line that contains one of the queries,
+// or with invert_match each line that contains none of them
+pub fn print_linenumbers(config: &Config, contents: &str) -> Vec<String> {
+    let mut results = Vec::new();
+    // Iterate over the lines with their line numbers (starting from 1)
+    for (line_number, line) in contents.lines().enumerate() {
+        let found = config.queries.iter().any(|query| line.contains(query.as_str()));
+        if found != config.invert_match {
+            // Format the line as "line_number: line"
+            results.push(format!("{}: {}", line_number + 1, line));
+        }
+    }
+    results
+}
+// Walk `folder` and run `work` on every file -r should search. Files are searched on a rayon
+// pool (unless --threads 1), and results are handed to `consume` in walk order, so the
+// output is the same as a serial search
+fn search_tree<R: Send>(
+    config: &Config,
+    folder: &Path,
+    work: &(impl Fn(&Path, io::Result<&mut dyn BufRead>) -> R + Sync),
+    consume: &mut impl FnMut(&Path, Visit<'_, R>) -> ControlFlow<()>,
+) -> ControlFlow<()> {
+    let files = search_order(config, folder);
+
+    // A thread count of 0 lets rayon pick one per CPU. --in-place edits one file at a time,
+    // in order, so nothing after the point where -q or --max-matches ends the run is rewritten
+    let pool = match config.threads {
+        _ if config.in_place.is_some() => None,
+        Some(1) => None,
+        threads => ThreadPoolBuilder::new().num_threads(threads.unwrap_or(0)).build().ok(),
+    };
+    let Some(pool) = pool else {
+        for walked in &files {
+            // Unreadable files are passed on too, so the caller can say why they were skipped
+            open_walked(config, walked, |path, reader| consume(path, Visit::Open(reader)))?;
+        }
+        return ControlFlow::Continue(());
+    };
+
+    // Set when the consumer is done, so workers skip the files still queued
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            pool.install(|| {
+                files.par_iter().enumerate().for_each_with(sender, |sender, (index, walked)| {
+                    if !stop.load(Ordering::Relaxed) {
+                        let _ = sender.send((index, open_walked(config, walked, work)));
+                    }
+                });
+            });
+        });
+
+        // Results arrive in any order; hold each one until every earlier file is consumed
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        for (index, result) in receiver {
+            waiting.insert(index, result);
+            while let Some(result) = waiting.remove(&next) {
+                if consume(walked_path(&files[next]), Visit::Done(result)).is_break() {
+                    stop.store(true, Ordering::Relaxed);
+                    return ControlFlow::Break(());
+                }
+                next += 1;
+            }
+        }
+        ControlFlow::Continue(())
+    })
+}
+// The files under `folder` in the order -r searches them: the walk's own order, which sorts
+// each directory's entries by name, or else the whole tree's files sorted together by --sort.
+// Ties, such as files of the same size, fall back to the path so the order stays the same
+// from run to run
+fn search_order(config: &Config, folder: &Path) -> Vec<Walked> {
+    let files = collect_files(config, folder);
+    let Some(key) = config.sort else {
+        return files;
+    };
+    // Read each file's metadata once rather than on every comparison. A file whose
+    // metadata can't be read sorts as if it were the oldest or smallest
+    let value = |path: &Path| -> Option<u128> {
+        if key == SortKey::Path {
+            return None;
+        }
+        let metadata = fs::metadata(path).ok()?;
+        match key {
+            SortKey::Modified => {
+                Some(metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+            }
+            _ => Some(metadata.len().into()),
+        }
+    };
+    let mut keyed: Vec<(Option<u128>, Walked)> =
+        files.into_iter().map(|walked| (value(walked_path(&walked)), walked)).collect();
+    keyed.sort_by(|(a, a_walked), (b, b_walked)| {
+        let (a_path, b_path) = (walked_path(a_walked), walked_path(b_walked));
+        let order = if key == SortKey::Path { a_path.cmp(b_path) } else { a.cmp(b) };
+        let order = if config.sort_reverse { order.reverse() } else { order };
+        order.then_with(|| a_path.cmp(b_path))
+    });
+    keyed.into_iter().map(|(_, walked)| walked).collect()
+}
+// Whether the file's extension belongs to any of the named FILE_TYPES
+fn is_of_types(path: &Path, types: &[String]) -> bool {
+    let Some(extension) = path.extension() else {
+        return false;
+    };
+    FILE_TYPES
+        .iter()
+        .filter(|(name, _)| types.iter().any(|wanted| wanted == name))
+        .any(|(_, extensions)| extensions.iter().any(|ext| extension == *ext))
+}
+// A file found by walking a folder, or a path below it that couldn't be read and why
+type Walked = Result<PathBuf, (PathBuf, io::Error)>;
+
+// The path of a walked entry, whether or not it could be read
+fn walked_path(walked: &Walked) -> &Path {
+    match walked {
+        Ok(path) | Err((path, _)) => path,
+    }
+}
+// Every file under `folder` that -r should search, along with whatever couldn't be read.
+// By default the ignore crate applies .gitignore (even outside a git repository), .ignore
+// and the global gitignore, while --no-ignore keeps the plain walkdir walk. Either way
+// hidden entries such as .git are skipped unless --hidden is given, and each directory's
+// entries are sorted by name, so the order doesn't depend on the platform. With --follow
+// both walkers check each linked directory against the ones above it, so a symlink loop
+// ends that branch instead of recursing forever
+fn collect_files(config: &Config, folder: &Path) -> Vec<Walked> {
+    let walked: Vec<Walked> = if config.respect_ignore {
+        WalkBuilder::new(folder)
+            .require_git(false)
+            .hidden(!config.hidden)
+            .max_depth(config.max_depth)
+            .follow_links(config.follow_links)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build()
+            .filter_map(|entry| match entry {
+                Ok(entry) if entry.file_type().is_some_and(|kind| kind.is_file()) => {
+                    Some(Ok(entry.into_path()))
+                }
+                Ok(_) => None,
+                Err(err) => {
+                    if let ignore::Error::Loop { ancestor, child } = innermost(&err) {
+                        report_loop(config, child, ancestor);
+                    }
+                    walk_io_error(&err, folder).map(Err)
+                }
+            })
+            .collect()
+    } else {
+        // WalkDir performs a depth-first search by default
+        WalkDir::new(folder)
+            .max_depth(config.max_depth.unwrap_or(usize::MAX))
+            .follow_links(config.follow_links)
+            .sort_by_file_name()
+            .into_iter()
+            // The folder itself is never skipped, even when it's "." or a hidden directory
+            .filter_entry(|entry| {
+                let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+                config.hidden || entry.depth() == 0 || !is_hidden
+            })
+            .filter_map(|entry| match entry {
+                Ok(entry) if entry.file_type().is_file() => Some(Ok(entry.into_path())),
+                Ok(_) => None,
+                Err(err) => {
+                    if let (Some(child), Some(ancestor)) = (err.path(), err.loop_ancestor()) {
+                        report_loop(config, child, ancestor);
+                    }
+                    let path = err.path().unwrap_or(folder).to_path_buf();
+                    err.into_io_error().map(|e| Err((path, e)))
+                }
+            })
+            .collect()
+    };
+
+    // The globs were checked while parsing, so none of them fail to compile here
+    let compile = |globs: &[String]| -> Vec<Pattern> {
+        globs.iter().filter_map(|glob| Pattern::new(glob).ok()).collect()
+    };
+    let includes = compile(&config.include_globs);
+    let excludes = compile(&config.exclude_globs);
+    // A glob may name just the file (*.rs) or a path below the folder (src/*.rs)
+    let matches = |pattern: &Pattern, path: &Path| {
+        pattern.matches_path(path.strip_prefix(folder).unwrap_or(path))
+            || path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+    };
+    // A .searchignore glob may also name a directory, which leaves out everything below it
+    let search_ignores = read_search_ignore(config, folder);
+    let search_ignored = |path: &Path| {
+        path.ancestors()
+            .take_while(|ancestor| *ancestor != folder)
+            .any(|ancestor| search_ignores.iter().any(|p| matches(p, ancestor)))
+    };
+    // Oversized files are dropped before anything is read from them
+    let fits = |path: &Path| {
+        config.max_filesize.is_none_or(|max| fs::metadata(path).is_ok_and(|m| m.len() <= max))
+    };
+    let wanted = |path: &Path| {
+        (includes.is_empty() || includes.iter().any(|p| matches(p, path)))
+            && !excludes.iter().any(|p| matches(p, path))
+            && !search_ignored(path)
+            && (config.types.is_empty() || is_of_types(path, &config.types))
+            && fits(path)
+    };
+    walked
+        .into_iter()
+        .filter(|walked| match walked {
+            Ok(path) => wanted(path),
+            // What couldn't be read is reported, unless .searchignore leaves it out anyway
+            Err((path, _)) => !search_ignored(path),
+        })
+        .collect()
+}
+// The path and I/O error behind a walk error, such as a missing folder or a directory that
+// can't be listed. Other problems, like a bad glob in a .gitignore, name no file to report
+fn walk_io_error(err: &ignore::Error, folder: &Path) -> Option<(PathBuf, io::Error)> {
+    let e = err.io_error()?;
+    let path = match err {
+        ignore::Error::WithPath { path, .. } => path.clone(),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            return walk_io_error(err, folder);
+        }
+        _ => folder.to_path_buf(),
+    };
+    Some((path, io::Error::new(e.kind(), e.to_string())))
+}
+// The globs in `folder`/.searchignore, one per line, skipping blank lines and # comments.
+// A missing file means no globs; a bad glob is reported and left out
+fn read_search_ignore(config: &Config, folder: &Path) -> Vec<Pattern> {
+    let path = folder.join(".searchignore");
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|glob| match Pattern::new(glob) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                if !config.no_messages {
+                    eprintln!("grep: {}: invalid glob '{}': {}", path.display(), glob, e);
+                }
+                None
+            }
+        })
+        .collect()
+}
+// The error an ignore walk error wraps, with the path and depth details taken off
+fn innermost(err: &ignore::Error) -> &ignore::Error {
+    match err {
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => innermost(err),
+        err => err,
+    }
+}
+// A followed symlink that leads back to a directory above it ends that branch of the walk,
+// which would otherwise go unexplained
+fn report_loop(config: &Config, link: &Path, ancestor: &Path) {
+    if !config.no_messages {
+        eprintln!(
+            "grep: {}: symbolic link loop back to {}, not followed",
+            link.display(),
+            ancestor.display()
+        );
+    }
+}
+// Color every match in place. The slices come from match_ranges at byte offsets into the
+// original line, so back-to-back hits (aa twice in aaaa), overlapping queries, -i hits that
+// differ in case from the query and -w/-x hits are colored exactly as they were matched
+fn highlight_query(line: &str, config: &Config, matcher: &Matcher) -> String {
+    let mut highlighted = String::with_capacity(line.len());
+    let mut last_index = 0;
+    for (start, end) in match_ranges(config, matcher, line) {
+        highlighted.push_str(&line[last_index..start]);
+        highlighted.push_str(&line[start..end].color(config.match_color).bold().to_string());
+        last_index = end;
+    }
+    highlighted.push_str(&line[last_index..]);
+    highlighted
+}
+// The line with every match swapped for `replacement`, which is highlighted when coloring
+fn replace_matches(line: &str, replacement: &str, matcher: &Matcher, config: &Config) -> String {
+    substitute_with(line, replacement, matcher, config, |replaced| {
+        if config.colored_output {
+            replaced.color(config.match_color).bold().to_string()
+        } else {
+            replaced
+        }
+    })
+}
+// The line with every match swapped for `replacement` as it is
+fn substitute(line: &str, replacement: &str, matcher: &Matcher, config: &Config) -> String {
+    substitute_with(line, replacement, matcher, config, |replaced| replaced)
+}
+// The line with every match swapped for `replacement`, each passed through `finish`. Under
+// -E the replacement is expanded the way the regex crate does it, so $1 or ${name} stands
+// for a capture group and $$ for a literal $; literal patterns have no groups, and their
+// replacement is used as it is
+fn substitute_with(
+    line: &str,
+    replacement: &str,
+    matcher: &Matcher,
+    config: &Config,
+    finish: impl Fn(String) -> String,
+) -> String {
+    let use_regex = config.use_regex && !config.fixed_strings;
+    if let (Some(re), None) = (&matcher.regex, config.fuzzy) {
+        return re
+            .replace_all(line, |caps: &regex::Captures| {
+                // Empty matches are left alone, as match_ranges leaves them out
+                if caps[0].is_empty() {
+                    return String::new();
+                }
+                // -i puts literal queries through a regex too, but they have no groups
+                let mut expanded = String::new();
+                if use_regex {
+                    caps.expand(replacement, &mut expanded);
+                } else {
+                    expanded.push_str(replacement);
+                }
+                finish(expanded)
+            })
+            .into_owned();
+    }
+    let mut replaced = String::with_capacity(line.len());
+    let mut last_index = 0;
+    for (start, end) in match_ranges(config, matcher, line) {
+        replaced.push_str(&line[last_index..start]);
+        replaced.push_str(&finish(replacement.to_string()));
+        last_index = end;
+    }
+    replaced.push_str(&line[last_index..]);
+    replaced
+}
+// Rewrite one file with every match replaced, returning whether anything changed. The new
+// text goes to a temporary file next to it that is then renamed over the original, so the
+// file is never left half written. A UTF-16 file is written back as UTF-16 in the same byte
+// order, byte order mark and all. Binary files are left alone
+fn edit_in_place(
+    config: &Config,
+    matcher: &Matcher,
+    file_path: &Path,
+    replacement: &str,
+    backup_suffix: &str,
+) -> io::Result<bool> {
+    let bytes = fs::read(file_path)?;
+    let encoding = utf16_encoding(&bytes);
+    // Malformed text fails rather than being written back with replacement characters
+    let contents = match encoding {
+        Some(encoding) => encoding
+            .decode_without_bom_handling_and_without_replacement(&bytes[2..])
+            .map(|text| text.into_owned())
+            .ok_or(io::ErrorKind::InvalidData)?,
+        None => String::from_utf8(bytes).map_err(|_| io::ErrorKind::InvalidData)?,
+    };
+    if looks_binary(config, &mut BufReader::new(contents.as_bytes()))? {
+        return Ok(false);
+    }
+
+    let terminator = if config.null_data { '\0' } else { '\n' };
+    let mut edited = String::with_capacity(contents.len());
+    let mut changed = false;
+    // Only the lines the search would select are edited, and -m stops it as it stops the
+    // search, so the file ends up as --replace without --in-place showed it
+    let mut selected = 0;
+    for line in contents.split_inclusive(terminator) {
+        // Keep the line ending out of the match and put it back afterwards
+        let text = line.strip_suffix(terminator).unwrap_or(line);
+        let text = if config.null_data { text } else { text.strip_suffix('\r').unwrap_or(text) };
+        let ending = &line[text.len()..];
+        if config.max_count.is_some_and(|max| selected >= max) || !is_match(config, matcher, text) {
+            edited.push_str(line);
+            continue;
+        }
+        selected += 1;
+        let replaced = substitute(text, replacement, matcher, config);
+        changed |= replaced != text;
+        edited.push_str(&replaced);
+        edited.push_str(ending);
+    }
+    if !changed {
+        return Ok(false);
+    }
+
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let (temp_path, mut temp_file) = create_temp_file(file_path)?;
+    let written = match encoding {
+        Some(encoding) => temp_file.write_all(&encode_utf16(&edited, encoding)),
+        None => temp_file.write_all(edited.as_bytes()),
+    };
+    // Closed before the rename, which some platforms refuse for an open file
+    drop(temp_file);
+    let replaced = written
+        .and_then(|_| fs::metadata(file_path))
+        .and_then(|metadata| fs::set_permissions(&temp_path, metadata.permissions()))
+        .and_then(|_| {
+            if !backup_suffix.is_empty() {
+                let backup = file_path.with_file_name(format!("{}{}", file_name, backup_suffix));
+                fs::copy(file_path, backup)?;
+            }
+            fs::rename(&temp_path, file_path)
+        });
+    if replaced.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    replaced.map(|_| true)
+}
+// Create a new, empty file next to `file_path` under a name nothing else is using, not even
+// another grep editing the same file at the same time
+fn create_temp_file(file_path: &Path) -> io::Result<(PathBuf, fs::File)> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    loop {
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        let temp_name = format!(".{}.{}-{}.grep-tmp", file_name, process::id(), id);
+        let temp_path = file_path.with_file_name(temp_name);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+// Where a printed line, or with -o a single match, sits in its file
+#[derive(Clone, Copy)]
+struct Location {
+    line_number: usize,
+    byte_offset: usize,
+    // Columns the line number is right-aligned to (0 leaves it unpadded)
+    number_width: usize,
+}
+// Define the print_result function to handle printing logic. Selected lines use ':' after
+// the filename and line number while context lines use '-', as in GNU grep
+fn print_result(
+    out: &mut dyn Write,
+    file_path: &Path,
+    location: Location,
+    line: &str,
+    separator: char,
+    matcher: &Matcher,
+    config: &Config,
+) -> io::Result<()> {
+    // Whatever --trim cuts from the front moves -b along to the first byte still printed
+    let (line, location) = if config.trim {
+        let trimmed = line.trim_start();
+        let byte_offset = location.byte_offset + (line.len() - trimmed.len());
+        (trimmed.trim_end(), Location { byte_offset, ..location })
+    } else {
+        (line, location)
+    };
+    // Cut before coloring, so no escape sequence is split
+    let truncated;
+    let line = match config.max_columns {
+        Some(max) => {
+            truncated = truncate_columns(line, max, matcher, config);
+            truncated.as_str()
+        }
+        None => line,
+    };
+    let line = if let (Some(replacement), ':') = (&config.replacement, separator) {
+        // Only selected lines (':') are rewritten; context is shown as it is
+        replace_matches(line, replacement, matcher, config)
+    } else if config.colored_output && config.only_matching {
+        // With -o the whole text is the match
+        line.color(config.match_color).bold().to_string()
+    } else if config.colored_output {
+        highlight_query(line, config, matcher)
+    } else {
+        line.to_string()
+    };
+    let separator = field_separator(separator, config);
+    // -n and -b stack up as line:offset:text
+    let line = if config.byte_offset {
+        let offset = paint(location.byte_offset, config.line_number_color, config);
+        format!("{}{}{}", offset, separator, line)
+    } else {
+        line
+    };
+    // A heading names the file, so the line number is what locates each line
+    let line = if config.line_number || config.heading {
+        let width = location.number_width;
+        let number = format!("{:>width$}", location.line_number);
+        format!("{}{}{}", paint(number, config.line_number_color, config), separator, line)
+    } else {
+        line
+    };
+
+    let terminator = if config.null_data { '\0' } else { '\n' };
+    let path = paint(displayed_path(file_path, config), config.path_color, config);
+    if shows_filenames(config) && !config.heading && config.null_separator {
+        write!(out, "{}\0{}{}", path, line, terminator)
+    } else if shows_filenames(config) && !config.heading {
+        // Use `file_path`
+        write!(out, "{}{}{}{}", path, separator, line, terminator)
+    } else {
+        write!(out, "{}{}", line, terminator)
+    }
+}
+// What goes between two fields of a printed line: ':' (selected) or '-' (context) and a
+// space, or the --field-separator string on selected lines and a bare '-' on context ones
+fn field_separator(separator: char, config: &Config) -> String {
+    match (&config.field_separator, separator) {
+        (Some(custom), ':') => paint(custom, config.separator_color, config),
+        (Some(_), separator) => paint(separator, config.separator_color, config),
+        (None, separator) => format!("{} ", paint(separator, config.separator_color, config)),
+    }
+}
+// At most `max` characters of the line, with … where text was left out. The window starts
+// the line, or with --max-columns-preview sits a little before the first match
+fn truncate_columns(line: &str, max: usize, matcher: &Matcher, config: &Config) -> String {
+    let length = line.chars().count();
+    if length <= max {
+        return line.to_string();
+    }
+    let start = if config.max_columns_preview {
+        let first = match_ranges(config, matcher, line)
+            .first()
+            .map_or(0, |&(start, _)| line[..start].chars().count());
+        first.saturating_sub(max / 4).min(length - max)
+    } else {
+        0
+    };
+    let window: String = line.chars().skip(start).take(max).collect();
+    let before = if start > 0 { "…" } else { "" };
+    let after = if start + max < length { "…" } else { "" };
+    format!("{}{}{}", before, window, after)
+}
+// Print a selected line once per match as file:line:column:text. An inverted or empty match
+// has no text to point at, so its row starts at column 1
+fn print_vimgrep(
+    out: &mut dyn Write,
+    file_path: &Path,
+    line_number: usize,
+    line: &str,
+    matcher: &Matcher,
+    config: &Config,
+) -> io::Result<()> {
+    let columns: Vec<usize> = match match_ranges(config, matcher, line) {
+        ranges if config.invert_match || ranges.is_empty() => vec![1],
+        ranges => ranges.iter().map(|&(start, _)| start + 1).collect(),
+    };
+    // Columns still count from the start of the untrimmed line, where an editor will look
+    let line = if config.trim { line.trim() } else { line };
+    for column in columns {
+        writeln!(out, "{}:{}:{}:{}", displayed_path(file_path, config), line_number, column, line)?;
+    }
+    Ok(())
+}
+// A path as it is printed: --path-separator replaces every separator the platform accepts
+// (both / and \ on Windows). Files are still opened by their real path
+fn displayed_path(file_path: &Path, config: &Config) -> String {
+    let shown = file_path.display().to_string();
+    match config.path_separator {
+        Some(separator) => shown
+            .chars()
+            .map(|c| if std::path::is_separator(c) { separator } else { c })
+            .collect(),
+        None => shown,
+    }
+}
+// Color one field of the output, or leave it plain when not coloring
+fn paint(text: impl fmt::Display, color: Color, config: &Config) -> String {
+    if config.colored_output {
+        text.to_string().color(color).to_string()
+    } else {
+        text.to_string()
+    }
+}
+// Like grep, name the file on each line whenever more than one file could be involved
+fn shows_filenames(config: &Config) -> bool {
+    let several_files = config.file_paths.len() > 1 || config.recursive_search;
+    !config.no_filename && (config.print_filenames || several_files)
+}
+// Print the --count summary for one file, naming it whenever several files are involved
+fn print_count(
+    out: &mut dyn Write,
+    file_path: &Path,
+    count: usize,
+    config: &Config,
+) -> io::Result<()> {
+    let path = paint(displayed_path(file_path, config), config.path_color, config);
+    if shows_filenames(config) && config.null_separator {
+        writeln!(out, "{}\0{}", path, count)
+    } else if shows_filenames(config) {
+        writeln!(out, "{}{}{}", path, field_separator(':', config), count)
+    } else {
+        writeln!(out, "{}", count)
+    }
+}
@@ -1,33 +1,130 @@
 /**
  * 
  * 
-Usage: grep [OPTIONS] <pattern> <files...>
+Usage: grep [OPTIONS] <pattern> [files...]
+Reads standard input when no files are given
+Files may be wildcards such as *.md, where ** spans directories as in src/**/*.rs
+Exits 0 if a line was selected, 1 if none was, and 2 on an error
+Short options can be combined, as in -in or -nA3
+With -r, files and directories matching a glob in DIR/.searchignore (one per line, # starts a comment) are never searched, even with --no-ignore
+An empty pattern selects every line; it matches no text, so -o prints nothing for it
+GREP_COLORS sets the ms, fn, ln and se colors as GNU grep does, e.g. ms=01;31:fn=35 (--color-* options win)
 Options:
--i                Case-insensitive search
--n                Print line numbers
--v                Invert match (exclude lines that match the pattern)
--r                Recursive directory search
--f                Print filenames
--c                Enable colored output
+-i, --ignore-case Case-insensitive search, by Unicode simple case folding (σ matches ς, but ß doesn't match ss)
+-n, --line-number Print line numbers
+-v, --invert-match Invert match (exclude lines that match the pattern)
+-r, --recursive   Recursive directory search (of the current directory if no files are given)
+-f, --with-filename Print filenames (automatic when searching several files or recursively)
+-c, --color[=WHEN] Color matches; WHEN is auto (the default: only on a terminal), always or never. -c means always
+-E, --regex       Interpret the pattern as a regular expression
+-A NUM            Print NUM lines of trailing context after each match
+-B NUM            Print NUM lines of leading context before each match
+-C NUM            Print NUM lines of context on both sides (a later -A/-B wins)
+-o, --only-matching Print only the matched parts of each line
+--count           Print only a count of selected lines per file (can't be combined with --count-matches)
+-m, --max-count NUM Stop reading a file after NUM selected lines
+-l                Print only the names of files with a selected line
+-L                Print only the names of files without a selected line
+-q, --quiet       Print nothing; exit 0 on the first match, 1 if none
+-s, --no-messages Suppress error messages about unreadable files
+-w                Match only whole words
+-x                Match only whole lines
+-e PATTERN        Use PATTERN; repeat to match any of several patterns
+--patterns-file PATH Read patterns from PATH, one per line (blank lines are ignored)
+--smart-case      Ignore case unless a pattern contains an uppercase letter (-i wins)
+--threads NUM     Search recursively with NUM threads (0 picks one per CPU, 1 is serial). Files searched ahead of their turn keep up to 1 MiB of output each in memory; a file with more is searched again when its turn comes
+--color-match COLOR Highlight matches in COLOR (a name such as yellow or bright blue; red by default)
+--no-filename     Never print filenames; beats both -f and the automatic prefix
+--heading         Print each file's path once above its numbered lines, with a blank line between files
+--vimgrep         Print file:line:column:text for every match, the column being a 1-based byte offset
+--json            Print one JSON object per selected line, with the byte offsets of each match
+--no-ignore       With -r, also search files that .gitignore/.ignore rules would skip
+--gitignore       With -r, skip files that .gitignore/.ignore rules exclude (the default; undoes --no-ignore)
+--include GLOB    With -r, only search files whose name matches GLOB (repeatable)
+--exclude GLOB    With -r, skip files whose name matches GLOB (repeatable; beats --include)
+--max-depth NUM   With -r, descend at most NUM levels; the directory given is depth 0, so 1 searches only its own files
+-a, --text        Search binary files (a NUL byte near the start) as text instead of just reporting a match
+-z, --null-data   Input and output lines end with a NUL byte instead of a newline
+-Z, --null        End each printed filename with a NUL byte instead of ':' (or a newline with -l/-L)
+-b, --byte-offset Print the 0-based byte offset of each line's start (of each match with -o) before it
+--replace TEXT    Print selected lines with every match replaced by TEXT (files are left untouched). With -E, $1 or ${name} inserts a capture group and $$ a literal $
+--in-place[=SUFFIX] With --replace, rewrite the files themselves, keeping the original as FILE+SUFFIX if one is given (not with -v, --pre or --search-zip)
+--stats           After the results, print counts of files searched, lines selected and matches, and the time taken, to stderr
+--max-filesize SIZE With -r, skip files larger than SIZE bytes; K, M and G suffixes multiply by 1024
+--hidden          With -r, also search hidden files and directories (names starting with '.')
+-t, --type TYPE   With -r, only search files of TYPE, such as rust or markdown (repeatable)
+--type-list       Show the types -t knows and their extensions
+--search-zip      Decompress files ending in .gz and search their contents
+--count-matches, --only-count-matches Print only a count of matches per file, counting every occurrence on a line, as rg -co does
+--group-separator STR Separate groups of context lines with STR instead of --
+--no-group-separator Don't print anything between groups of context lines
+--line-number-width[=N] With -n, right-align line numbers to N columns, or to fit the file's last line number if N is left out
+--color-path COLOR Color filenames in COLOR when coloring (magenta by default)
+--color-line COLOR Color line numbers and byte offsets in COLOR when coloring (green by default)
+--color-separator COLOR Color the separators after filenames and line numbers, and between context groups, in COLOR (cyan by default)
+--follow          With -r, follow symbolic links to directories (a link back to an enclosing directory is not walked again)
+--files           List the files a search would read (walking directories as -r does) without searching them; takes no pattern
+--total           With --count or --count-matches, finish with a total: N line summing the counts of every file
+-F, --fixed-strings Match the pattern literally, even with -E
+--all-match       With several patterns, select only lines that match every one of them (not just any)
+--and-not PATTERN Reject lines that also contain PATTERN (honors -i, -E and -F)
+--fuzzy NUM       Experimental: select lines where some stretch of text is within NUM edits of a pattern, taken literally. Much slower than a normal search, and matches aren't highlighted
+--multiline       With -E, match against the whole file so a match can span lines; every line a match touches is selected, so -A/-B count from a match's first and last lines
+--trim            Strip leading and trailing whitespace from printed lines (matching still sees the whole line; -b points at the first printed byte)
+--max-columns NUM Print at most NUM characters of each line, marking a cut with …; matching still sees the whole line
+--max-columns-preview With --max-columns, show the part of a long line around its first match instead of its start
+--pre COMMAND     Search what COMMAND prints when run with each file's path as its argument (e.g. pdftotext) instead of the file itself. COMMAND runs on every file searched, so only use one you trust
+--field-separator STR Put STR instead of ': ' between the filename, line number, offset and text of selected lines (and of --count lines); context lines then use a bare '-'
+--binary          Report each match in a binary file as its byte offset (Binary file F matches at offset N) instead of a single line for the file
+--dotall          With -E, let . match a newline too (only useful with --multiline or -z)
+--multiline-regex With --multiline, let ^ and $ match at every line break; otherwise they only match at the start and end of the file
+--max-matches NUM Stop the whole search once NUM lines have been selected, across all files (-m is per file)
+--o-separator STR With -o, print all the matches of a line on one line, joined by STR
+--path-separator CHAR Print paths with CHAR in place of the platform's path separators (e.g. / on Windows); files are still opened by their real paths
+--files-from PATH Also search the files listed in PATH, one per line (- reads the list from standard input; blank lines and # comments are skipped)
+--sort KEY        With -r, search all the files found, across the whole tree, ordered by KEY: path, modified (oldest first) or size (smallest first); ties go by path
+--sortr KEY       Like --sort, but in reverse, e.g. --sortr modified puts the most recently modified files first
 -h, --help        Show help information
  * 
  * 
 */
 use std::env;
+use std::io::{self, Write};
 use std::process;
-use grep::Config;  // Import the Config struct from your grep module
+use grep::{Config, ConfigError};  // Import the Config struct from your grep module
 
 fn main() {
     // Parse command-line arguments and build the Config struct
-    let config = Config::build(env::args()).unwrap_or_else(|err| {
-        eprintln!("{err}");
-        process::exit(1);
+    let config = Config::build(env::args()).unwrap_or_else(|err| match err {
+        // Asking for help is not a mistake, so print it normally and succeed. A reader that
+        // stops early (grep --help | head -1) closes the pipe, which is no failure either
+        ConfigError::HelpRequested(usage) => {
+            match writeln!(io::stdout().lock(), "{usage}") {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                    eprintln!("{e}");
+                    process::exit(2);
+                }
+                _ => process::exit(0),
+            }
+        }
+        err => {
+            eprintln!("{err}");
+            process::exit(2);
+        }
     });
 
-    // If the run function returns an error, handle it
-    if let Err(e) = grep::run(config) {
-        eprintln!("Application error: {e}");
-        process::exit(1);
+    let quiet = config.quiet;
+
+    // Exit like grep: 0 if a line was selected, 1 if none was, 2 on an error
+    match grep::run(config) {
+        // A file that couldn't be read is an error, unless -q already found its match
+        Ok(summary) if summary.had_errors && !(quiet && summary.matched) => process::exit(2),
+        Ok(summary) if summary.matched => {}
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("Application error: {e}");
+            process::exit(2);
+        }
     }
 }
 
@@ -0,0 +1,860 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use grep::{Config, ConfigError};
+
+// A scratch directory of test files under the system temp dir, removed again on drop
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("grep-test-{}-{}", std::process::id(), id));
+        fs::create_dir_all(&dir).unwrap();
+        Fixture { dir }
+    }
+
+    // Write `contents` to `name` below the fixture, creating any directories on the way
+    fn file(&self, name: &str, contents: impl AsRef<[u8]>) -> &Fixture {
+        let path = self.path(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        self
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    // Run the binary from inside the fixture, with no color settings leaking in
+    fn grep(&self, args: &[&str]) -> Output {
+        grep_in(&self.dir, args)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn grep_in(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_grep"))
+        .args(args)
+        .current_dir(dir)
+        .env_remove("GREP_COLORS")
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn later_context_option_wins() {
+    let fixture = Fixture::new();
+    fixture.file("ctx.txt", "one\ntwo\nthree\nfour\nfive\n");
+
+    let output = fixture.grep(&["-n", "-C", "1", "-A", "0", "three", "ctx.txt"]);
+    assert_eq!(stdout(&output), "2- two\n3: three\n");
+
+    let output = fixture.grep(&["-n", "-A", "0", "-C", "1", "three", "ctx.txt"]);
+    assert_eq!(stdout(&output), "2- two\n3: three\n4- four\n");
+}
+
+#[test]
+fn context_needs_a_number() {
+    let fixture = Fixture::new();
+    fixture.file("ctx.txt", "three\n");
+
+    let output = fixture.grep(&["-C", "x", "three", "ctx.txt"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid value 'x' for option -C"));
+}
+
+#[test]
+fn exit_status_says_whether_anything_matched() {
+    let fixture = Fixture::new();
+    fixture.file("notes.txt", "alpha\nbeta\n");
+
+    assert_eq!(fixture.grep(&["alpha", "notes.txt"]).status.code(), Some(0));
+    assert_eq!(fixture.grep(&["gamma", "notes.txt"]).status.code(), Some(1));
+    assert_eq!(fixture.grep(&["alpha", "missing.txt"]).status.code(), Some(2));
+    assert_eq!(fixture.grep(&["--no-such-option", "alpha"]).status.code(), Some(2));
+}
+
+// A name that isn't valid UTF-8 must neither panic nor be skipped
+#[cfg(unix)]
+#[test]
+fn searches_non_utf8_paths() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let fixture = Fixture::new();
+    let dir = fixture.dir.join(OsStr::from_bytes(b"caf\xe9"));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join(OsStr::from_bytes(b"menu\xff.txt")), "espresso\n").unwrap();
+
+    let found = grep::search_recursive("espresso", &fixture.dir);
+    assert_eq!(found.len(), 1);
+    assert!(found[0].ends_with(": espresso"));
+
+    let output = fixture.grep(&["-r", "espresso"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.ends_with(b".txt: espresso\n"));
+}
+
+#[test]
+fn recursive_search_reports_paths_it_cannot_walk() {
+    let fixture = Fixture::new();
+    fixture.file("d/a.txt", "foo\n");
+
+    let output = fixture.grep(&["-r", "foo", "nope", "d"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "d/a.txt: foo\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "grep: nope: No such file or directory\n");
+
+    // -s only silences the message
+    let output = fixture.grep(&["-rs", "foo", "nope", "d"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stderr.is_empty());
+}
+
+// İ lowercases to two characters, so offsets taken from a lowercased copy would be wrong
+#[test]
+fn case_insensitive_matches_keep_their_offsets_in_non_ascii_text() {
+    let fixture = Fixture::new();
+    fixture.file("menu.txt", "İİ un CAFÉ noir\nthé vert\n");
+
+    let output = fixture.grep(&["-i", "-o", "café", "menu.txt"]);
+    assert_eq!(stdout(&output), "CAFÉ\n");
+
+    let output = fixture.grep(&["-i", "--color=always", "café", "menu.txt"]);
+    assert_eq!(stdout(&output), "İİ un \u{1b}[1;31mCAFÉ\u{1b}[0m noir\n");
+
+    let output = fixture.grep(&["-i", "-o", "THÉ", "menu.txt"]);
+    assert_eq!(stdout(&output), "thé\n");
+}
+
+#[test]
+fn whole_line_match_counts_trailing_whitespace_and_empty_lines() {
+    let fixture = Fixture::new();
+    fixture.file("lines.txt", "exact\nexact  \n\n  \nnot exact\n");
+
+    let output = fixture.grep(&["-n", "-x", "exact", "lines.txt"]);
+    assert_eq!(stdout(&output), "1: exact\n");
+
+    // Only the truly empty line equals an empty pattern, not the one holding spaces
+    let output = fixture.grep(&["-n", "-x", "", "lines.txt"]);
+    assert_eq!(stdout(&output), "3: \n");
+
+    let output = fixture.grep(&["-n", "-x", "-v", "exact", "lines.txt"]);
+    assert_eq!(stdout(&output), "2: exact  \n3: \n4:   \n5: not exact\n");
+}
+
+#[test]
+fn short_options_can_be_combined() {
+    let config = Config::build_from_slice(&["-in", "needle", "notes.txt"]).unwrap();
+    assert!(config.case_insensitive && config.line_number);
+
+    let config = Config::build_from_slice(&["-rfc", "needle", "."]).unwrap();
+    assert!(config.recursive_search && config.print_filenames && config.colored_output);
+
+    let error = Config::build_from_slice(&["-iyn", "needle", "notes.txt"]).err();
+    assert_eq!(error, Some(ConfigError::UnknownOption("-y".to_string())));
+}
+
+#[test]
+fn parallel_search_prints_the_same_as_a_serial_one() {
+    let fixture = Fixture::new();
+    for dir in 0..5 {
+        for file in 0..20 {
+            let contents: String = (0..30)
+                .map(|line| {
+                    let hit = if (line + file) % 7 == 0 { "hit" } else { "" };
+                    format!("line {} {}\n", line, hit)
+                })
+                .collect();
+            fixture.file(&format!("tree/{}/{}.txt", dir, file), contents);
+        }
+    }
+
+    for args in [&["-n", "hit"][..], &["-n", "-A1", "hit"], &["--heading", "-n", "hit"]] {
+        let serial = fixture.grep(&[&["-r", "--threads", "1"], args, &["tree"]].concat());
+        let parallel = fixture.grep(&[&["-r", "--threads", "4"], args, &["tree"]].concat());
+        assert!(!serial.stdout.is_empty());
+        assert_eq!(stdout(&serial), stdout(&parallel));
+    }
+}
+
+// Lines read from a pipe that is still open have to come out before the pipe closes
+#[test]
+fn matches_from_standard_input_are_printed_as_they_arrive() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_grep"))
+        .arg("foo")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"foo\nbar\n").unwrap();
+
+    let mut reader = BufReader::new(child.stdout.take().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        let _ = reader.read_line(&mut line);
+        let _ = sender.send(line);
+    });
+    let line = receiver.recv_timeout(Duration::from_secs(10));
+
+    drop(stdin);
+    child.wait().unwrap();
+    assert_eq!(line.as_deref(), Ok("foo\n"));
+}
+
+// The search reads a file a line at a time, so one bigger than the memory it may use still works
+#[cfg(target_os = "linux")]
+#[test]
+fn searches_a_file_larger_than_its_memory_limit() {
+    use std::io::Write;
+
+    let fixture = Fixture::new();
+    let mut file = fs::File::create(fixture.path("big.txt")).unwrap();
+    let line = format!("{}\n", "a".repeat(99));
+    for _ in 0..640_000 {
+        file.write_all(line.as_bytes()).unwrap();
+    }
+    file.write_all(b"needle\n").unwrap();
+    drop(file);
+
+    // 40 MB of address space, for a 64 MB file
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("ulimit -v 40000; exec '{}' needle big.txt", env!("CARGO_BIN_EXE_grep")))
+        .current_dir(&fixture.dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "needle\n");
+}
+
+#[test]
+fn heading_groups_matches_under_each_file() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "foo\nbar\nfoo2\n").file("b.txt", "bar\n").file("c.txt", "xfoo\n");
+
+    let output = fixture.grep(&["--heading", "foo", "a.txt", "b.txt", "c.txt"]);
+    assert_eq!(stdout(&output), "a.txt\n1: foo\n3: foo2\n\nc.txt\n1: xfoo\n");
+}
+
+// The starting directory is depth 0, so --max-depth 1 covers just the files directly inside it
+#[test]
+fn max_depth_counts_the_starting_directory_as_zero() {
+    let fixture = Fixture::new();
+    fixture
+        .file("d/one.txt", "foo\n")
+        .file("d/e/two.txt", "foo\n")
+        .file("d/e/f/three.txt", "foo\n");
+
+    let output = fixture.grep(&["-r", "--max-depth", "0", "foo", "d"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    let output = fixture.grep(&["-r", "--max-depth", "1", "foo", "d"]);
+    assert_eq!(stdout(&output), "d/one.txt: foo\n");
+
+    let output = fixture.grep(&["-r", "--max-depth", "2", "foo", "d"]);
+    assert_eq!(stdout(&output), "d/e/two.txt: foo\nd/one.txt: foo\n");
+}
+
+#[test]
+fn binary_files_are_reported_unless_text_is_given() {
+    let fixture = Fixture::new();
+    fixture.file("blob.bin", b"foo\0bar\nbaz\n").file("none.bin", b"\0\0baz\n");
+
+    let output = fixture.grep(&["foo", "blob.bin"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "Binary file blob.bin matches\n");
+
+    let output = fixture.grep(&["foo", "none.bin"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    for text in ["-a", "--text"] {
+        let output = fixture.grep(&[text, "foo", "blob.bin"]);
+        assert_eq!(output.stdout, b"foo\0bar\n");
+    }
+}
+
+#[test]
+fn crlf_line_endings_are_not_part_of_the_line() {
+    let fixture = Fixture::new();
+    fixture.file("dos.txt", "foo\r\nbar foo\r\n");
+
+    let output = fixture.grep(&["-x", "foo", "dos.txt"]);
+    assert_eq!(stdout(&output), "foo\n");
+
+    let output = fixture.grep(&["-E", "--color=always", "foo$", "dos.txt"]);
+    assert_eq!(stdout(&output), "\u{1b}[1;31mfoo\u{1b}[0m\nbar \u{1b}[1;31mfoo\u{1b}[0m\n");
+}
+
+// -r searches in the order of the name-sorted walk, whichever walker and however many threads
+#[test]
+fn recursive_search_order_is_fixed() {
+    let fixture = Fixture::new();
+    for name in ["tree/c.txt", "tree/b/x.txt", "tree/a.txt", "tree/a/z/y.txt", "tree/a/m.txt"] {
+        fixture.file(name, "foo\n");
+    }
+
+    let expected = "tree/a/m.txt: foo\ntree/a/z/y.txt: foo\ntree/a.txt: foo\n\
+                    tree/b/x.txt: foo\ntree/c.txt: foo\n";
+    for args in [&["-r"][..], &["-r", "--no-ignore"], &["-r", "--threads", "1"]] {
+        let output = fixture.grep(&[args, &["foo", "tree"]].concat());
+        assert_eq!(stdout(&output), expected);
+    }
+}
+
+// --sort orders every file under the tree together, not directory by directory
+#[test]
+fn sort_orders_the_whole_tree_by_size_or_modification_time() {
+    let fixture = Fixture::new();
+    fixture
+        .file("tree/a/big.txt", "foo foo foo foo foo\n")
+        .file("tree/b/small.txt", "foo\n")
+        .file("tree/c.txt", "foo foo foo\n");
+    let epoch = std::time::SystemTime::UNIX_EPOCH;
+    let times = [("tree/c.txt", 1_000), ("tree/a/big.txt", 2_000), ("tree/b/small.txt", 3_000)];
+    for (name, secs) in times {
+        let file = fs::File::options().write(true).open(fixture.path(name)).unwrap();
+        file.set_modified(epoch + std::time::Duration::from_secs(secs)).unwrap();
+    }
+
+    let files = |args: &[&str]| {
+        let output = fixture.grep(&[args, &["-l", "foo", "tree"]].concat());
+        stdout(&output)
+    };
+    assert_eq!(files(&["-r", "--sort", "size"]), "tree/b/small.txt\ntree/c.txt\ntree/a/big.txt\n");
+    assert_eq!(files(&["-r", "--sortr", "size"]), "tree/a/big.txt\ntree/c.txt\ntree/b/small.txt\n");
+    assert_eq!(
+        files(&["-r", "--sort", "modified"]),
+        "tree/c.txt\ntree/a/big.txt\ntree/b/small.txt\n"
+    );
+    assert_eq!(
+        files(&["-r", "--sortr", "modified"]),
+        "tree/b/small.txt\ntree/a/big.txt\ntree/c.txt\n"
+    );
+}
+
+#[test]
+fn a_file_given_twice_is_searched_once() {
+    let fixture = Fixture::new();
+    fixture.file("file.txt", "foo\nbar\n").file("other.txt", "foo\n");
+
+    let output = fixture.grep(&["foo", "file.txt", "file.txt"]);
+    assert_eq!(stdout(&output), "foo\n");
+
+    // Different spellings of one path count as the same file, and the first one is kept
+    let output = fixture.grep(&["foo", "file.txt", "other.txt", "./file.txt"]);
+    assert_eq!(stdout(&output), "file.txt: foo\nother.txt: foo\n");
+}
+
+// The binary expands the pattern itself, as it would when a shell leaves it alone
+#[test]
+fn double_star_globs_reach_nested_files() {
+    let fixture = Fixture::new();
+    fixture
+        .file("src/top.rs", "foo\n")
+        .file("src/top.txt", "foo\n")
+        .file("src/a/mid.rs", "foo\n")
+        .file("src/a/b/deep.rs", "foo\n")
+        .file("src/a/b/deep.txt", "foo\n");
+
+    let output = fixture.grep(&["foo", "src/**/*.rs"]);
+    assert_eq!(stdout(&output), "src/a/b/deep.rs: foo\nsrc/a/mid.rs: foo\nsrc/top.rs: foo\n");
+
+    // Directories a glob matches are only searched with -r
+    let output = fixture.grep(&["foo", "src/*"]);
+    assert_eq!(stdout(&output), "src/top.rs: foo\nsrc/top.txt: foo\n");
+}
+
+#[test]
+fn hidden_files_are_searched_only_with_hidden() {
+    let fixture = Fixture::new();
+    fixture.file(".env", "SECRET=1\n").file("visible.txt", "SECRET=2\n");
+
+    for walker in ["--gitignore", "--no-ignore"] {
+        let output = fixture.grep(&["-r", walker, "SECRET"]);
+        assert_eq!(stdout(&output), "./visible.txt: SECRET=2\n");
+
+        let output = fixture.grep(&["-r", walker, "--hidden", "SECRET"]);
+        assert_eq!(stdout(&output), "./.env: SECRET=1\n./visible.txt: SECRET=2\n");
+    }
+
+    // A hidden directory named on the command line is still searched
+    fixture.file(".config/app.env", "SECRET=3\n");
+    let output = fixture.grep(&["-r", "SECRET", ".config"]);
+    assert_eq!(stdout(&output), ".config/app.env: SECRET=3\n");
+}
+
+#[test]
+fn in_place_rewrites_the_file_and_keeps_a_backup() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "cat here\nno\r\ncat cat\n").file("b.txt", "no cats\n");
+
+    let args = ["--replace", "dog", "--in-place=.bak", "-w", "cat", "a.txt", "b.txt"];
+    let output = fixture.grep(&args);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+    assert_eq!(fs::read_to_string(fixture.path("a.txt")).unwrap(), "dog here\nno\r\ndog dog\n");
+    assert_eq!(fs::read_to_string(fixture.path("a.txt.bak")).unwrap(), "cat here\nno\r\ncat cat\n");
+    // A file without a match is neither rewritten nor backed up
+    assert_eq!(fs::read_to_string(fixture.path("b.txt")).unwrap(), "no cats\n");
+    assert!(!fixture.path("b.txt.bak").exists());
+
+    // Nothing is left behind besides the files and the backup
+    let mut names: Vec<_> = fs::read_dir(&fixture.dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["a.txt", "a.txt.bak", "b.txt"]);
+}
+
+// Files past the one that ends the run are left alone, even when searched on several threads
+#[test]
+fn in_place_stops_with_the_search() {
+    let fixture = Fixture::new();
+    for file in 0..8 {
+        fixture.file(&format!("tree/{}.txt", file), "cat\n");
+    }
+
+    let output = fixture.grep(&["-rq", "--threads", "4", "--replace", "dog", "--in-place", "cat"]);
+    assert_eq!(output.status.code(), Some(0));
+    let edited = (0..8)
+        .map(|file| fs::read_to_string(fixture.path(&format!("tree/{}.txt", file))).unwrap())
+        .filter(|contents| contents == "dog\n")
+        .count();
+    assert_eq!(edited, 1);
+}
+
+#[test]
+fn in_place_refuses_what_it_cannot_edit() {
+    let error = Config::build_from_slice(&["--replace", "dog", "--in-place", "cat"]).err();
+    let required = "files to edit (standard input can't be rewritten)".to_string();
+    assert_eq!(error, Some(ConfigError::RequiresOption("--in-place".to_string(), required)));
+
+    let error = Config::build_from_slice(&["-v", "--replace", "dog", "--in-place", "cat", "a.txt"]);
+    let conflict = ConfigError::ConflictingOptions("--in-place".to_string(), "-v".to_string());
+    assert_eq!(error.err(), Some(conflict));
+
+    let args = ["--search-zip", "--replace", "dog", "--in-place", "cat", "a.txt.gz"];
+    let conflict = ConfigError::ConflictingOptions("--in-place".into(), "--search-zip".into());
+    assert_eq!(Config::build_from_slice(&args).err(), Some(conflict));
+
+    let args = ["--pre", "pdftotext", "--replace", "dog", "--in-place", "cat", "a.pdf"];
+    let conflict = ConfigError::ConflictingOptions("--in-place".into(), "--pre".into());
+    assert_eq!(Config::build_from_slice(&args).err(), Some(conflict));
+}
+
+// Paths that expand to nothing are an error, never a fallback to `.` or standard input
+#[test]
+fn paths_that_expand_to_nothing_search_nothing() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "foo\n").file("empty.lst", "");
+
+    let output = fixture.grep(&["-r", "--replace", "X", "--in-place", "foo", "*.nomatch"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(fs::read_to_string(fixture.path("a.txt")).unwrap(), "foo\n");
+
+    let output = fixture.grep(&["foo", "*.nomatch"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+
+    let output = fixture.grep(&["--files-from", "empty.lst", "-r", "foo"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+}
+
+fn utf16le(text: &str) -> Vec<u8> {
+    let units = std::iter::once(0xFEFF).chain(text.encode_utf16());
+    units.flat_map(u16::to_le_bytes).collect()
+}
+
+#[test]
+fn utf16_files_are_searched_and_edited_as_utf16() {
+    let fixture = Fixture::new();
+    fixture.file("wide.txt", utf16le("café au lait\nthé\n"));
+
+    let output = fixture.grep(&["-n", "café", "wide.txt"]);
+    assert_eq!(stdout(&output), "1: café au lait\n");
+
+    let output = fixture.grep(&["--replace", "crème", "--in-place=.orig", "lait", "wide.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    let edited = fs::read(fixture.path("wide.txt")).unwrap();
+    assert_eq!(edited, utf16le("café au crème\nthé\n"));
+    assert_eq!(fs::read(fixture.path("wide.txt.orig")).unwrap(), utf16le("café au lait\nthé\n"));
+}
+
+#[test]
+fn grep_colors_falls_back_to_the_defaults_when_unset_or_malformed() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "foo\n");
+    let colored = |grep_colors: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_grep"));
+        command.args(["-f", "--color=always", "foo", "a.txt"]).current_dir(&fixture.dir);
+        match grep_colors {
+            Some(value) => command.env("GREP_COLORS", value),
+            None => command.env_remove("GREP_COLORS"),
+        };
+        let output = command.output().unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stderr.is_empty());
+        stdout(&output)
+    };
+    let painted = |path: &str, text: &str| {
+        format!("\u{1b}[{}ma.txt\u{1b}[0m\u{1b}[36m:\u{1b}[0m \u{1b}[{}mfoo\u{1b}[0m\n", path, text)
+    };
+
+    assert_eq!(colored(None), painted("35", "1;31"));
+    assert_eq!(colored(Some("ms=01;32:fn=34")), painted("34", "1;32"));
+    for malformed in ["", "garbage", "ms=zz;;:=:fn", "ms=38;5:fn=999"] {
+        assert_eq!(colored(Some(malformed)), painted("35", "1;31"), "GREP_COLORS={}", malformed);
+    }
+    // The valid entries of a partly malformed value still apply
+    assert_eq!(colored(Some("bogus:ms=01;32")), painted("35", "1;32"));
+}
+
+#[test]
+fn recursive_search_of_files_is_a_plain_search() {
+    let fixture = Fixture::new();
+    fixture.file("f.txt", "foo\nbar\n").file("g.txt", "foo\n");
+
+    for files in [&["f.txt"][..], &["f.txt", "g.txt"], &["missing"], &["f.txt", "missing"]] {
+        let plain = fixture.grep(&[&["-n", "foo"], files].concat());
+        let recursive = fixture.grep(&[&["-rn", "foo"], files].concat());
+        assert_eq!(recursive.status.code(), plain.status.code());
+        assert_eq!(stdout(&recursive), stdout(&plain));
+        assert_eq!(recursive.stderr, plain.stderr);
+    }
+
+    let output = fixture.grep(&["-r", "foo", "missing"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr, "grep: missing: No such file or directory\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_walks_symlinked_directories() {
+    use std::os::unix::fs::symlink;
+
+    let fixture = Fixture::new();
+    fixture.file("real/sub/deep.txt", "foo\n").file("root/own.txt", "foo\n");
+    symlink("../real", fixture.path("root/link")).unwrap();
+
+    for walker in ["--gitignore", "--no-ignore"] {
+        let output = fixture.grep(&["-r", walker, "foo", "root"]);
+        assert_eq!(stdout(&output), "root/own.txt: foo\n");
+
+        let output = fixture.grep(&["-r", walker, "--follow", "foo", "root"]);
+        assert_eq!(stdout(&output), "root/link/sub/deep.txt: foo\nroot/own.txt: foo\n");
+    }
+
+    // A link back to a directory above it is reported and not walked again
+    symlink(".", fixture.path("root/self")).unwrap();
+    let output = fixture.grep(&["-r", "--follow", "foo", "root"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "root/link/sub/deep.txt: foo\nroot/own.txt: foo\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("root/self: symbolic link loop"), "{}", stderr);
+}
+
+// -v --count counts the lines printed without --count, the ones that don't match
+#[test]
+fn inverted_count_counts_the_lines_without_a_match() {
+    let fixture = Fixture::new();
+    fixture.file("pets.txt", "cat\ndog\ncat dog\nbird\n");
+
+    assert_eq!(stdout(&fixture.grep(&["--count", "cat", "pets.txt"])), "2\n");
+    assert_eq!(stdout(&fixture.grep(&["-v", "--count", "cat", "pets.txt"])), "2\n");
+    assert_eq!(stdout(&fixture.grep(&["-v", "--count", "dog", "pets.txt"])), "2\n");
+    assert_eq!(stdout(&fixture.grep(&["-v", "--count", "i", "pets.txt"])), "3\n");
+
+    let output = fixture.grep(&["-v", "--count", "", "pets.txt"]);
+    assert_eq!(stdout(&output), "0\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn a_missing_file_is_reported_and_the_rest_are_still_searched() {
+    let fixture = Fixture::new();
+    fixture.file("present.txt", "foo\nbar\n");
+
+    for args in [["foo", "missing.txt", "present.txt"], ["foo", "present.txt", "missing.txt"]] {
+        let output = fixture.grep(&args);
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(stdout(&output), "present.txt: foo\n");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr, "grep: missing.txt: No such file or directory\n");
+    }
+}
+
+#[test]
+fn replace_escapes_a_dollar_sign_with_two() {
+    let fixture = Fixture::new();
+    fixture.file("prices.txt", "price 5 USD\n");
+
+    let replaced = |args: &[&str]| stdout(&fixture.grep(&[args, &["prices.txt"]].concat()));
+    assert_eq!(replaced(&["-E", "--replace", "$$$1", r"price (\d)"]), "$5 USD\n");
+    assert_eq!(replaced(&["-E", "--replace", "$$1", r"price (\d)"]), "$1 USD\n");
+    assert_eq!(replaced(&["-E", "--replace", "${n}$$", r"price (?<n>\d)"]), "5$ USD\n");
+    // Without -E the replacement is taken literally
+    assert_eq!(replaced(&["--replace", "$$1", "price"]), "$$1 5 USD\n");
+}
+
+// -i folds one character at a time: every form of sigma matches the others and k matches
+// the Kelvin sign, but ß never matches the two letters ss
+#[test]
+fn case_insensitive_search_uses_simple_case_folding() {
+    let fixture = Fixture::new();
+    fixture.file("words.txt", "ΟΔΟΣ\nοδος\nοδοσ\n5 \u{212a}\nkelvin\nStraße\nSTRASSE\nSTRAẞE\n");
+    let lines = |query: &str| stdout(&fixture.grep(&["-i", "-n", query, "words.txt"]));
+
+    let sigmas = "1: ΟΔΟΣ\n2: οδος\n3: οδοσ\n";
+    assert_eq!(lines("οδοσ"), sigmas);
+    assert_eq!(lines("ΟΔΟς"), sigmas);
+
+    assert_eq!(lines("k"), "4: 5 \u{212a}\n5: kelvin\n");
+    assert_eq!(lines("\u{212a}ELVIN"), "5: kelvin\n");
+
+    assert_eq!(lines("ß"), "6: Straße\n8: STRAẞE\n");
+    assert_eq!(lines("strasse"), "7: STRASSE\n");
+    assert_eq!(lines("STRAẞE"), "6: Straße\n8: STRAẞE\n");
+}
+
+#[test]
+fn back_to_back_matches_are_highlighted_separately() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "aaaa\naaa\n");
+    let red = |text: &str| format!("\u{1b}[1;31m{}\u{1b}[0m", text);
+
+    // Literal, case-insensitive and regex searches all highlight the same way
+    for mode in [&[][..], &["-i"], &["-E"]] {
+        let output = fixture.grep(&[mode, &["--color=always", "aa", "a.txt"]].concat());
+        assert_eq!(stdout(&output), format!("{}{}\n{}a\n", red("aa"), red("aa"), red("aa")));
+    }
+
+    let output = fixture.grep(&["-o", "aa", "a.txt"]);
+    assert_eq!(stdout(&output), "aa\naa\naa\n");
+}
+
+#[test]
+fn whole_word_highlighting_skips_cat_inside_a_word() {
+    let fixture = Fixture::new();
+    fixture.file("c.txt", "concatenate cat\nconcatenate\n");
+
+    let output = fixture.grep(&["-w", "--color=always", "cat", "c.txt"]);
+    assert_eq!(stdout(&output), "concatenate \u{1b}[1;31mcat\u{1b}[0m\n");
+
+    let output = fixture.grep(&["-w", "-o", "cat", "c.txt"]);
+    assert_eq!(stdout(&output), "cat\n");
+}
+
+#[test]
+fn count_and_count_matches_count_different_things() {
+    let fixture = Fixture::new();
+    fixture.file("r.txt", "a a a\nb\na\n");
+
+    assert_eq!(stdout(&fixture.grep(&["--count", "a", "r.txt"])), "2\n");
+    assert_eq!(stdout(&fixture.grep(&["--count-matches", "a", "r.txt"])), "4\n");
+
+    let output = fixture.grep(&["--count", "--count-matches", "a", "r.txt"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Options --count and --count-matches can't be used together"));
+}
+
+// A hit that fails -w mustn't hide an overlapping one that passes
+#[test]
+fn whole_word_match_looks_past_a_rejected_hit() {
+    let fixture = Fixture::new();
+    fixture.file("w.txt", "xab ab ab\n");
+
+    for mode in [&[][..], &["-i"], &["-E"]] {
+        let output = fixture.grep(&[mode, &["-w", "-o", "ab ab", "w.txt"]].concat());
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(stdout(&output), "ab ab\n");
+    }
+}
+
+// A reader that stops early, like head -1, ends the search quietly with the usual status
+#[test]
+fn a_closed_pipe_is_not_an_error() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let fixture = Fixture::new();
+    let lines: String = (0..200_000).map(|line| format!("foo {}\n", line)).collect();
+    fixture.file("many.txt", lines);
+
+    for args in [&["foo", "many.txt"][..], &["-r", "--files"], &["bar", "many.txt"]] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_grep"))
+            .args(args)
+            .current_dir(&fixture.dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut first = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut first).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+        let expected = if first.is_empty() { 1 } else { 0 };
+        assert_eq!(output.status.code(), Some(expected), "{:?}", args);
+    }
+}
+
+// Output too big to hold while earlier files print is searched again in its turn, and
+// comes out the same
+#[test]
+fn parallel_search_prints_a_file_with_lots_of_output_in_order() {
+    let fixture = Fixture::new();
+    let big: String = (0..40_000).map(|line| format!("hit {:050}\n", line)).collect();
+    fixture.file("tree/a.txt", "hit a\n").file("tree/b.txt", big).file("tree/c.txt", "hit c\n");
+
+    for args in [&["-n", "hit"][..], &["--max-matches", "30000", "hit"]] {
+        let serial = fixture.grep(&[&["-r", "--threads", "1"], args, &["tree"]].concat());
+        let parallel = fixture.grep(&[&["-r", "--threads", "4"], args, &["tree"]].concat());
+        assert!(serial.stdout.len() > 1 << 20);
+        assert_eq!(stdout(&serial), stdout(&parallel));
+    }
+}
+
+// --no-filename beats the automatic prefix for several files, and -f too
+#[test]
+fn no_filename_wins_over_every_other_filename_setting() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "foo a\n").file("b.txt", "foo b\n");
+
+    assert_eq!(stdout(&fixture.grep(&["foo", "a.txt", "b.txt"])), "a.txt: foo a\nb.txt: foo b\n");
+    assert_eq!(stdout(&fixture.grep(&["-f", "foo", "a.txt"])), "a.txt: foo a\n");
+    for args in [&["--no-filename"][..], &["-f", "--no-filename"], &["--no-filename", "-f"]] {
+        let output = fixture.grep(&[args, &["foo", "a.txt", "b.txt"]].concat());
+        assert_eq!(stdout(&output), "foo a\nfoo b\n", "{:?}", args);
+    }
+}
+
+// Offsets count every byte of the file, the "\r" of a CRLF ending included
+#[test]
+fn byte_offsets_point_at_lines_and_with_o_at_matches() {
+    let fixture = Fixture::new();
+    fixture.file("b.txt", "one\r\nfoo two foo\r\nthree\nfoo\n");
+
+    let output = fixture.grep(&["-b", "foo", "b.txt"]);
+    assert_eq!(stdout(&output), "5: foo two foo\n24: foo\n");
+
+    let output = fixture.grep(&["-bo", "foo", "b.txt"]);
+    assert_eq!(stdout(&output), "5: foo\n13: foo\n24: foo\n");
+
+    let output = fixture.grep(&["-nb", "two", "b.txt"]);
+    assert_eq!(stdout(&output), "2: 5: foo two foo\n");
+}
+
+// The file ends up as the preview without --in-place showed it
+#[test]
+fn in_place_edits_only_the_lines_the_search_selects() {
+    let fixture = Fixture::new();
+    let original = "foo one\nfoo skip\nbar\nfoo bar\nfoo two\n";
+    let cases: [(&[&str], &str); 3] = [
+        (&["--and-not", "skip", "foo"], "X one\nfoo skip\nbar\nX bar\nX two\n"),
+        (&["-m1", "foo"], "X one\nfoo skip\nbar\nfoo bar\nfoo two\n"),
+        (&["--all-match", "-e", "foo", "-e", "bar"], "foo one\nfoo skip\nbar\nX X\nfoo two\n"),
+    ];
+    for (args, expected) in cases {
+        fixture.file("f.txt", original);
+        let preview = fixture.grep(&[&["--replace", "X"], args, &["f.txt"]].concat());
+        fixture.grep(&[&["--replace", "X", "--in-place"], args, &["f.txt"]].concat());
+
+        let edited = fs::read_to_string(fixture.path("f.txt")).unwrap();
+        assert_eq!(edited, expected, "{:?}", args);
+        // Every line the preview printed is in the edited file
+        assert!(stdout(&preview).lines().all(|line| edited.lines().any(|edit| edit == line)));
+    }
+}
+
+#[test]
+fn max_filesize_skips_larger_files_under_r() {
+    let fixture = Fixture::new();
+    fixture
+        .file("d/small.txt", "foo\n")
+        .file("d/exact.txt", "foo\n".repeat(256))
+        .file("d/large.txt", "foo\n".repeat(257))
+        .file("d/huge.txt", "foo\n".repeat(300_000));
+
+    // 1K is 1024 bytes, and a file of exactly that size is still searched
+    for size in ["1K", "1k", "1024"] {
+        let output = fixture.grep(&["-r", "--count", "--max-filesize", size, "foo", "d"]);
+        assert_eq!(stdout(&output), "d/exact.txt: 256\nd/small.txt: 1\n", "{}", size);
+    }
+    let output = fixture.grep(&["-r", "--count", "--max-filesize", "1M", "foo", "d"]);
+    assert_eq!(stdout(&output), "d/exact.txt: 256\nd/large.txt: 257\nd/small.txt: 1\n");
+
+    for size in ["1x", "KB", "-1", "", "1.5K", "99999999999G"] {
+        let output = fixture.grep(&["-r", "--max-filesize", size, "foo", "d"]);
+        assert_eq!(output.status.code(), Some(2), "{}", size);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let expected = format!("Invalid value '{}' for option --max-filesize", size);
+        assert!(stderr.contains(&expected), "{}", stderr);
+    }
+}
+
+// An empty pattern selects every line but matches no text
+#[test]
+fn empty_pattern_selects_every_line_without_matching_text() {
+    let fixture = Fixture::new();
+    fixture.file("e.txt", "a\n\nb\n");
+
+    assert_eq!(stdout(&fixture.grep(&["", "e.txt"])), "a\n\nb\n");
+    assert_eq!(stdout(&fixture.grep(&["--count", "", "e.txt"])), "3\n");
+    assert_eq!(stdout(&fixture.grep(&["--count-matches", "", "e.txt"])), "0\n");
+
+    let output = fixture.grep(&["-o", "", "e.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn library_searches_honor_multiline_and_max_matches() {
+    let fixture = Fixture::new();
+    fixture.file("a.txt", "start\nend\nfoo\nfoo\n").file("b.txt", "foo\n");
+    let (a, b) = (fixture.path("a.txt"), fixture.path("b.txt"));
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+    let args = ["-E", "--multiline", r"start\nend", a];
+    let config = Config::build_from_slice(&args).unwrap();
+    let matches = grep::search_matches(&config).unwrap();
+    let lines: Vec<_> = matches.iter().map(|found| (found.line_number, &found.line[..])).collect();
+    assert_eq!(lines, [(1, "start"), (2, "end")]);
+    let spans: Vec<_> = matches.iter().map(|found| found.submatches[0]).collect();
+    assert_eq!(spans, [grep::Submatch { start: 0, end: 5 }, grep::Submatch { start: 0, end: 3 }]);
+    assert_eq!(grep::search_iter(&config).filter_map(Result::ok).collect::<Vec<_>>(), matches);
+    assert_eq!(stdout(&fixture.grep(&args)), "start\nend\n");
+
+    let args = ["--max-matches", "2", "foo", a, b];
+    let config = Config::build_from_slice(&args).unwrap();
+    let matches = grep::search_matches(&config).unwrap();
+    assert_eq!(matches.iter().map(|found| found.line_number).collect::<Vec<_>>(), [3, 4]);
+    assert_eq!(grep::search_iter(&config).filter_map(Result::ok).collect::<Vec<_>>(), matches);
+}